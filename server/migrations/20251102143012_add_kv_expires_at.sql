@@ -0,0 +1,6 @@
+ALTER TABLE kv_store
+    ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS kv_store_expires_at_idx
+    ON kv_store (expires_at)
+    WHERE expires_at IS NOT NULL;
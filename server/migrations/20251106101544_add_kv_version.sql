@@ -0,0 +1,5 @@
+-- shared by all keys so that a version is never reused, even after a key is deleted and recreated
+CREATE SEQUENCE IF NOT EXISTS kv_store_version_seq;
+
+ALTER TABLE kv_store
+    ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT nextval('kv_store_version_seq');
@@ -0,0 +1,3 @@
+ALTER TABLE kv_store
+    ALTER COLUMN value TYPE BYTEA USING convert_to(value, 'UTF8'),
+    ADD COLUMN IF NOT EXISTS content_type TEXT NOT NULL DEFAULT 'text/plain; charset=utf-8';
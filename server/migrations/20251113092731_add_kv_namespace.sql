@@ -0,0 +1,6 @@
+ALTER TABLE kv_store
+    ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL DEFAULT 'default';
+
+ALTER TABLE kv_store
+    DROP CONSTRAINT IF EXISTS kv_store_pkey,
+    ADD PRIMARY KEY (namespace, key);
@@ -0,0 +1,15 @@
+-- previous values of each pair, recorded on every write and delete when history is enabled
+CREATE TABLE IF NOT EXISTS kv_history (
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    version BIGINT NOT NULL,
+    -- NULL for deletes
+    value BYTEA,
+    content_type TEXT,
+    expires_at TIMESTAMPTZ,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (namespace, key, version)
+);
+
+CREATE INDEX IF NOT EXISTS kv_history_recorded_at_idx
+    ON kv_history (recorded_at);
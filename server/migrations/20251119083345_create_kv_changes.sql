@@ -0,0 +1,13 @@
+-- ordered log of every change; sequence numbers are assigned under an advisory lock held until
+-- commit, so that they are committed in order
+CREATE TABLE IF NOT EXISTS kv_changes (
+    seq BIGSERIAL PRIMARY KEY,
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    op TEXT NOT NULL CHECK (op IN ('put', 'delete')),
+    -- NULL for deletes
+    version BIGINT,
+    value BYTEA,
+    content_type TEXT,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
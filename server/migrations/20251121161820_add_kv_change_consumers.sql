@@ -0,0 +1,14 @@
+-- checkpoints acknowledged by consumers of the change log, which is compacted up to the lowest one
+CREATE TABLE IF NOT EXISTS kv_change_consumers (
+    name TEXT PRIMARY KEY,
+    seq BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- single row holding the sequence number up to which the change log has been compacted
+CREATE TABLE IF NOT EXISTS kv_changes_compaction (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    compacted_through BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO kv_changes_compaction DEFAULT VALUES ON CONFLICT DO NOTHING;
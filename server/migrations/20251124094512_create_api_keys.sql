@@ -0,0 +1,9 @@
+-- API keys, stored as SHA-256 hashes of the bearer tokens, each with the scopes it grants per
+-- namespace, e.g. `[{"namespace": "*", "scope": "admin"}]`
+CREATE TABLE IF NOT EXISTS api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    key_hash BYTEA NOT NULL UNIQUE,
+    grants JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
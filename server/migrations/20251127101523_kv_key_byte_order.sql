@@ -0,0 +1,4 @@
+-- Keys compare byte-wise whatever the locale of the database, so that a prefix is a range of the
+-- primary key: keys starting with `abc` are those from `abc` up to `abd`
+ALTER TABLE kv_store
+    ALTER COLUMN key TYPE TEXT COLLATE "C";
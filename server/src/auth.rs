@@ -0,0 +1,371 @@
+use crate::error::AppError;
+use crate::namespace::{validate_namespace, DEFAULT_NAMESPACE};
+use crate::rate_limit;
+use crate::state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Path, ResourceDef, ServiceRequest, ServiceResponse, Url};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::Json;
+use sqlx::{PgExecutor, PgPool};
+use std::fmt;
+use std::time::Duration;
+use validator::{Validate, ValidationError};
+
+/// Namespace of grants covering every namespace. Routes not tied to a namespace require a grant on
+/// it.
+pub const ALL_NAMESPACES: &str = "*";
+
+/// Prefix of generated tokens, telling them apart from other secrets.
+const TOKEN_PREFIX: &str = "kv_";
+
+/// Keys looked up recently, kept so that every request does not query the database.
+const KEY_CACHE_CAPACITY: u64 = 10_000;
+
+/// What a key may do. Each scope implies the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read pairs, history and changes.
+    Read,
+    /// Write and delete pairs.
+    Write,
+    /// Flush namespaces, read stats and metrics and manage keys.
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+fn validate_grant_namespace(namespace: &str) -> Result<(), ValidationError> {
+    if namespace == ALL_NAMESPACES {
+        return Ok(());
+    }
+    validate_namespace(namespace)
+}
+
+/// A scope granted on a namespace, or on every namespace with [`ALL_NAMESPACES`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Validate)]
+pub struct Grant {
+    #[validate(custom(function = "validate_grant_namespace"))]
+    pub namespace: String,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub grants: Vec<Grant>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiKey {
+    /// Whether the key grants `scope` on `namespace`, or on every namespace if `None`.
+    pub fn allows(&self, namespace: Option<&str>, scope: Scope) -> bool {
+        self.grants.iter().any(|grant| {
+            grant.scope >= scope
+                && (grant.namespace == ALL_NAMESPACES
+                    || Some(grant.namespace.as_str()) == namespace)
+        })
+    }
+}
+
+/// Generates a new random token.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Tokens are random, so a fast hash is enough to keep them from being read back.
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Stores a new key named `name` for `token`. Returns `None` if the name is taken.
+pub async fn create(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    token: &str,
+    grants: &[Grant],
+) -> sqlx::Result<Option<ApiKey>> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO api_keys (name, key_hash, grants)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO NOTHING
+        RETURNING id, created_at
+        "#,
+        name,
+        hash(token),
+        Json(grants) as _
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.map(|row| ApiKey {
+        id: row.id,
+        name: name.to_string(),
+        grants: grants.to_vec(),
+        created_at: row.created_at,
+    }))
+}
+
+/// Stores the key named `name`, replacing its token and grants if it exists.
+pub async fn replace(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    token: &str,
+    grants: &[Grant],
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO api_keys (name, key_hash, grants)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET key_hash = EXCLUDED.key_hash, grants = EXCLUDED.grants
+        "#,
+        name,
+        hash(token),
+        Json(grants) as _
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list(executor: impl PgExecutor<'_>) -> sqlx::Result<Vec<ApiKey>> {
+    let rows = sqlx::query!(
+        r#"SELECT id, name, grants AS "grants: Json<Vec<Grant>>", created_at FROM api_keys ORDER BY name"#
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ApiKey {
+            id: row.id,
+            name: row.name,
+            grants: row.grants.0,
+            created_at: row.created_at,
+        })
+        .collect())
+}
+
+/// Deletes the key named `name`. Returns `false` if there is none.
+pub async fn remove(executor: impl PgExecutor<'_>, name: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query!("DELETE FROM api_keys WHERE name = $1", name)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn find(executor: impl PgExecutor<'_>, key_hash: &[u8]) -> sqlx::Result<Option<ApiKey>> {
+    let row = sqlx::query!(
+        r#"SELECT id, name, grants AS "grants: Json<Vec<Grant>>", created_at FROM api_keys WHERE key_hash = $1"#,
+        key_hash
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.map(|row| ApiKey {
+        id: row.id,
+        name: row.name,
+        grants: row.grants.0,
+        created_at: row.created_at,
+    }))
+}
+
+/// Authenticates requests with API keys. Disabled unless built with [`Auth::new`].
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    /// Keys by hash of their token. Unknown tokens are not remembered, so that made up ones cannot
+    /// push out valid keys. Other instances may take up to the time to live to notice a revoked
+    /// key.
+    keys: Option<moka::sync::Cache<Vec<u8>, ApiKey>>,
+}
+
+impl Auth {
+    /// Enables authentication, remembering looked up keys for `cache_ttl`.
+    pub fn new(cache_ttl: Duration) -> Self {
+        Self {
+            keys: Some(
+                moka::sync::Cache::builder()
+                    .max_capacity(KEY_CACHE_CAPACITY)
+                    .time_to_live(cache_ttl)
+                    .build(),
+            ),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// The key of `token`, if any.
+    async fn key(&self, db_pool: &PgPool, token: &str) -> sqlx::Result<Option<ApiKey>> {
+        let key_hash = hash(token);
+        if let Some(key) = self.keys.as_ref().and_then(|keys| keys.get(&key_hash)) {
+            return Ok(Some(key));
+        }
+
+        let key = find(db_pool, &key_hash).await?;
+        if let (Some(keys), Some(key)) = (&self.keys, &key) {
+            keys.insert(key_hash, key.clone());
+        }
+
+        Ok(key)
+    }
+
+    /// Forgets the keys looked up so far, once keys have been changed.
+    pub fn forget(&self) {
+        if let Some(keys) = &self.keys {
+            keys.invalidate_all();
+        }
+    }
+}
+
+/// What a request needs to be let through: a scope on a namespace, or on every namespace if
+/// `None`. `None` altogether for public routes.
+pub(crate) fn required(req: &ServiceRequest) -> Option<(Option<String>, Scope)> {
+    let pattern = req.match_pattern().unwrap_or_default();
+    if pattern.starts_with("/_health/") {
+        return None;
+    }
+    if pattern == "/stats" || pattern == "/metrics" || pattern.starts_with("/_keys") {
+        return Some((None, Scope::Admin));
+    }
+
+    // patterns are matched regardless of the method, so `GET /flush` reads the pair `flush`
+    let scope = if pattern.ends_with("/flush") && req.method() == Method::POST {
+        Scope::Admin
+    } else if pattern.ends_with("/_batch/get")
+        || matches!(*req.method(), Method::GET | Method::HEAD)
+    {
+        Scope::Read
+    } else {
+        Scope::Write
+    };
+    if pattern.starts_with("/_changes/consumers") {
+        return Some((None, scope));
+    }
+
+    let namespace = if pattern.starts_with("/ns/{namespace}") {
+        // requests are not routed yet: the namespace is captured the way the router will, decoded
+        let mut path = Path::new(Url::new(req.uri().clone()));
+        ResourceDef::prefix("/ns/{namespace}").capture_match_info(&mut path);
+        path.get("namespace").unwrap_or_default().to_string()
+    } else {
+        DEFAULT_NAMESPACE.to_string()
+    };
+    Some((Some(namespace), scope))
+}
+
+async fn authorize(
+    state: &AppState,
+    req: &ServiceRequest,
+    namespace: Option<&str>,
+    scope: Scope,
+) -> Result<ApiKey, AppError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| AppError::Unauthorized("missing bearer token".into()))?;
+    let key = state
+        .auth
+        .key(&state.db_pool, token)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("invalid API key".into()))?;
+
+    if !key.allows(namespace, scope) {
+        return Err(AppError::Forbidden(match namespace {
+            Some(namespace) => format!("{} scope required on namespace {}", scope, namespace),
+            None => format!("{} scope required on every namespace", scope),
+        }));
+    }
+
+    Ok(key)
+}
+
+/// Rejects requests without a bearer token granting the scope their route requires, if
+/// authentication is enabled. The key of an accepted request is left in its extensions.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    if let Some(state) = state.filter(|state| state.auth.is_enabled())
+        && let Some((namespace, scope)) = required(&req)
+    {
+        if let Err(err) = rate_limit::admit(&state.rate_limit, &req, scope) {
+            return Ok(req.error_response(err));
+        }
+        match authorize(&state, &req, namespace.as_deref(), scope).await {
+            Ok(key) => {
+                req.extensions_mut().insert(key);
+            }
+            Err(err) => {
+                // failures take from the budget of the address, made up tokens included
+                let err = rate_limit::count_failure(&state.rate_limit, &req, scope)
+                    .err()
+                    .unwrap_or(err);
+                return Ok(req.error_response(err));
+            }
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApiKey, Grant, Scope};
+    use chrono::Utc;
+
+    #[test]
+    fn scopes_imply_lower_ones() {
+        let key = ApiKey {
+            id: 1,
+            name: "key_1".into(),
+            grants: vec![
+                Grant {
+                    namespace: "ns_1".into(),
+                    scope: Scope::Write,
+                },
+                Grant {
+                    namespace: "*".into(),
+                    scope: Scope::Read,
+                },
+            ],
+            created_at: Utc::now(),
+        };
+
+        assert!(key.allows(Some("ns_1"), Scope::Read));
+        assert!(key.allows(Some("ns_1"), Scope::Write));
+        assert!(!key.allows(Some("ns_1"), Scope::Admin));
+        assert!(key.allows(Some("ns_2"), Scope::Read));
+        assert!(!key.allows(Some("ns_2"), Scope::Write));
+        assert!(key.allows(None, Scope::Read));
+        assert!(!key.allows(None, Scope::Write));
+    }
+}
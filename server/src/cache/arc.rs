@@ -0,0 +1,293 @@
+use super::{CacheEntry, CacheKey, CacheStore, Capacity};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Which {
+    /// Cached, read once since it was last cached.
+    T1,
+    /// Cached, read at least twice.
+    T2,
+    /// Evicted from `T1`, only the key is kept.
+    B1,
+    /// Evicted from `T2`, only the key is kept.
+    B2,
+}
+
+#[derive(Debug)]
+struct Slot {
+    /// `None` for evicted keys.
+    entry: Option<CacheEntry>,
+    weight: u64,
+    which: Which,
+    /// Position in its list, lowest first out.
+    stamp: u64,
+}
+
+/// Keys in least recently used order, by stamp.
+#[derive(Debug, Default)]
+struct List {
+    order: BTreeMap<u64, CacheKey>,
+    weight: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    slots: HashMap<CacheKey, Slot>,
+    t1: List,
+    t2: List,
+    b1: List,
+    b2: List,
+    /// Target weight of `T1`, adapted on every hit in `B1` or `B2`.
+    p: u64,
+    stamp: u64,
+}
+
+impl Lists {
+    fn list(&mut self, which: Which) -> &mut List {
+        match which {
+            Which::T1 => &mut self.t1,
+            Which::T2 => &mut self.t2,
+            Which::B1 => &mut self.b1,
+            Which::B2 => &mut self.b2,
+        }
+    }
+
+    /// Adds `key` as the most recently used key of `which`.
+    fn push(&mut self, key: CacheKey, entry: Option<CacheEntry>, weight: u64, which: Which) {
+        self.stamp += 1;
+        let stamp = self.stamp;
+        let list = self.list(which);
+        list.order.insert(stamp, key.clone());
+        list.weight += weight;
+        self.slots.insert(
+            key,
+            Slot {
+                entry,
+                weight,
+                which,
+                stamp,
+            },
+        );
+    }
+
+    fn take(&mut self, key: &CacheKey) -> Option<Slot> {
+        let slot = self.slots.remove(key)?;
+        let list = self.list(slot.which);
+        list.order.remove(&slot.stamp);
+        list.weight -= slot.weight;
+        Some(slot)
+    }
+
+    fn pop_lru(&mut self, which: Which) -> Option<(CacheKey, Slot)> {
+        let key = self.list(which).order.first_key_value()?.1.clone();
+        let slot = self.take(&key)?;
+        Some((key, slot))
+    }
+
+    /// Evicts the least recently used entry of `T1` or `T2`, depending on how `T1` compares to
+    /// its target, keeping its key in `B1` or `B2`.
+    fn replace(&mut self, hit_in_b2: bool) {
+        let from_t1 = self.t2.weight == 0
+            || (self.t1.weight > 0
+                && (self.t1.weight > self.p || (hit_in_b2 && self.t1.weight == self.p)));
+        let (from, to) = if from_t1 {
+            (Which::T1, Which::B1)
+        } else {
+            (Which::T2, Which::B2)
+        };
+
+        if let Some((key, slot)) = self.pop_lru(from) {
+            self.push(key, None, slot.weight, to);
+        }
+    }
+}
+
+/// Adaptive replacement cache (Megiddo and Modha): entries read once and entries read again are
+/// kept apart, and the share of each adapts to hits on recently evicted keys. A scan through many
+/// keys read only once therefore does not evict entries that are read repeatedly.
+///
+/// Sizes are measured in weight rather than entries, so that the cache can hold a byte budget.
+#[derive(Debug)]
+pub struct ArcStore {
+    capacity: Capacity,
+    lists: Mutex<Lists>,
+    evictions: AtomicU64,
+}
+
+impl ArcStore {
+    pub fn new(capacity: Capacity) -> Self {
+        Self {
+            capacity,
+            lists: Mutex::default(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CacheStore for ArcStore {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let mut lists = self.lists.lock().unwrap();
+        let slot = lists.slots.get(key)?;
+        let entry = slot.entry.clone()?;
+        if entry.is_expired() {
+            lists.take(key);
+            return None;
+        }
+
+        let slot = lists.take(key)?;
+        lists.push(key.clone(), slot.entry, slot.weight, Which::T2);
+        Some(entry)
+    }
+
+    fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        let weight = self.capacity.weigh(&key, &entry);
+        let limit = self.capacity.limit();
+        let mut lists = self.lists.lock().unwrap();
+        let previous = lists.take(&key).map(|slot| slot.which);
+        if weight > limit {
+            return;
+        }
+
+        // a hit on an evicted key shifts the target towards the list it was evicted from
+        match previous {
+            Some(Which::B1) => {
+                let delta = (lists.b2.weight / lists.b1.weight.max(1)).max(1) * weight;
+                lists.p = (lists.p + delta).min(limit);
+            }
+            Some(Which::B2) => {
+                let delta = (lists.b1.weight / lists.b2.weight.max(1)).max(1) * weight;
+                lists.p = lists.p.saturating_sub(delta);
+            }
+            _ => {}
+        }
+
+        while lists.t1.weight + lists.t2.weight + weight > limit
+            && lists.t1.weight + lists.t2.weight > 0
+        {
+            lists.replace(previous == Some(Which::B2));
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let which = if previous.is_some() {
+            Which::T2
+        } else {
+            Which::T1
+        };
+        lists.push(key, Some(entry), weight, which);
+
+        // evicted keys are remembered up to the capacity of the cache
+        while lists.t1.weight + lists.b1.weight > limit && lists.pop_lru(Which::B1).is_some() {}
+        while lists.t1.weight + lists.t2.weight + lists.b1.weight + lists.b2.weight > 2 * limit
+            && lists.pop_lru(Which::B2).is_some()
+        {}
+    }
+
+    fn remove(&self, key: &CacheKey) {
+        self.lists.lock().unwrap().take(key);
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        let lists = self.lists.lock().unwrap();
+        lists
+            .slots
+            .get(key)
+            .and_then(|slot| slot.entry.as_ref())
+            .is_some_and(|entry| !entry.is_expired())
+    }
+
+    fn clear(&self) {
+        *self.lists.lock().unwrap() = Lists::default();
+    }
+
+    fn clear_namespace(&self, namespace: &str) {
+        let mut lists = self.lists.lock().unwrap();
+        let keys: Vec<_> = lists
+            .slots
+            .keys()
+            .filter(|key| key.namespace == namespace)
+            .cloned()
+            .collect();
+        for key in keys {
+            lists.take(&key);
+        }
+    }
+
+    fn entry_count(&self) -> u64 {
+        let lists = self.lists.lock().unwrap();
+        (lists.t1.order.len() + lists.t2.order.len()) as u64
+    }
+
+    fn weighted_size(&self) -> u64 {
+        let lists = self.lists.lock().unwrap();
+        lists.t1.weight + lists.t2.weight
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Cache, CacheEntry, CacheKey, Capacity, Eviction};
+    use crate::store::TEXT_CONTENT_TYPE;
+
+    #[actix_web::test]
+    async fn arc_hit_and_miss_counts() {
+        let cache = Cache::with_capacity(Capacity::Entries(8), Eviction::Arc);
+
+        assert_eq!(cache.get(&CacheKey::new("ns", "invalid")).await, None);
+        assert_eq!(cache.stats().hits, 0);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None),
+            )
+            .await;
+        assert_eq!(
+            cache
+                .get(&CacheKey::new("ns", "key_1"))
+                .await
+                .map(|entry| entry.value),
+            Some("value_1".into())
+        );
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache.remove(&CacheKey::new("ns", "key_1")).await;
+        assert_eq!(cache.get(&CacheKey::new("ns", "key_1")).await, None);
+    }
+
+    #[actix_web::test]
+    async fn arc_keeps_frequent_entries_through_a_scan() {
+        let cache = Cache::with_capacity(Capacity::Entries(4), Eviction::Arc);
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+
+        for key in ["hot_1", "hot_2"] {
+            cache.insert(CacheKey::new("ns", key), entry.clone()).await;
+            cache.get(&CacheKey::new("ns", key)).await;
+        }
+        for key in ["scan_1", "scan_2", "scan_3", "scan_4"] {
+            cache.insert(CacheKey::new("ns", key), entry.clone()).await;
+        }
+
+        assert!(cache.contains(&CacheKey::new("ns", "hot_1")));
+        assert!(cache.contains(&CacheKey::new("ns", "hot_2")));
+        assert!(!cache.contains(&CacheKey::new("ns", "scan_1")));
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.stats().evictions, 2);
+
+        // a key evicted shortly before comes back as a frequent entry
+        cache
+            .insert(CacheKey::new("ns", "scan_1"), entry.clone())
+            .await;
+        assert!(cache.contains(&CacheKey::new("ns", "scan_1")));
+        assert!(cache.contains(&CacheKey::new("ns", "hot_1")));
+        assert!(cache.contains(&CacheKey::new("ns", "hot_2")));
+        assert_eq!(cache.len(), 4);
+    }
+}
@@ -0,0 +1,216 @@
+use super::{CacheEntry, CacheKey, CacheStore, Capacity};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug)]
+struct Slot {
+    entry: CacheEntry,
+    weight: u64,
+    /// Set when read, cleared when the hand passes by.
+    referenced: bool,
+    /// Tells this slot apart from earlier slots of the same key still on the ring.
+    generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct Clock {
+    slots: HashMap<CacheKey, Slot>,
+    /// Keys in the order the hand visits them. Keys removed since are skipped when visited.
+    ring: VecDeque<(CacheKey, u64)>,
+    weight: u64,
+    generation: u64,
+}
+
+impl Clock {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.weight -= slot.weight;
+        }
+
+        // keys that are removed rather than evicted would otherwise pile up on the ring
+        if self.ring.len() > 2 * self.slots.len() + 64 {
+            let slots = &self.slots;
+            self.ring.retain(|(key, generation)| {
+                slots
+                    .get(key)
+                    .is_some_and(|slot| slot.generation == *generation)
+            });
+        }
+    }
+
+    /// Advances the hand up to the first slot not read since its last visit and evicts it.
+    /// Returns `false` if there was nothing to evict.
+    fn evict(&mut self) -> bool {
+        while let Some((key, generation)) = self.ring.pop_front() {
+            let Some(slot) = self.slots.get_mut(&key) else {
+                continue;
+            };
+            if slot.generation != generation {
+                continue;
+            }
+
+            if slot.referenced {
+                slot.referenced = false;
+                self.ring.push_back((key, generation));
+            } else {
+                self.remove(&key);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// CLOCK (second chance) eviction: a hand sweeps the entries in insertion order, evicting the
+/// first one that has not been read since the hand last passed it.
+#[derive(Debug)]
+pub struct ClockStore {
+    capacity: Capacity,
+    clock: Mutex<Clock>,
+    evictions: AtomicU64,
+}
+
+impl ClockStore {
+    pub fn new(capacity: Capacity) -> Self {
+        Self {
+            capacity,
+            clock: Mutex::default(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CacheStore for ClockStore {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let mut clock = self.clock.lock().unwrap();
+        let slot = clock.slots.get_mut(key)?;
+        if slot.entry.is_expired() {
+            clock.remove(key);
+            return None;
+        }
+
+        slot.referenced = true;
+        Some(slot.entry.clone())
+    }
+
+    fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        let weight = self.capacity.weigh(&key, &entry);
+        let mut clock = self.clock.lock().unwrap();
+        // an overwritten entry keeps its mark, but goes back behind the hand
+        let referenced = clock.slots.get(&key).is_some_and(|slot| slot.referenced);
+        clock.remove(&key);
+        if weight > self.capacity.limit() {
+            return;
+        }
+
+        while clock.weight + weight > self.capacity.limit() && clock.evict() {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        clock.generation += 1;
+        let generation = clock.generation;
+        clock.ring.push_back((key.clone(), generation));
+        clock.slots.insert(
+            key,
+            Slot {
+                entry,
+                weight,
+                referenced,
+                generation,
+            },
+        );
+        clock.weight += weight;
+    }
+
+    fn remove(&self, key: &CacheKey) {
+        self.clock.lock().unwrap().remove(key);
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        let clock = self.clock.lock().unwrap();
+        clock
+            .slots
+            .get(key)
+            .is_some_and(|slot| !slot.entry.is_expired())
+    }
+
+    fn clear(&self) {
+        *self.clock.lock().unwrap() = Clock::default();
+    }
+
+    fn clear_namespace(&self, namespace: &str) {
+        let mut clock = self.clock.lock().unwrap();
+        clock.slots.retain(|key, _| key.namespace != namespace);
+        clock.ring.retain(|(key, _)| key.namespace != namespace);
+        clock.weight = clock.slots.values().map(|slot| slot.weight).sum();
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.clock.lock().unwrap().slots.len() as u64
+    }
+
+    fn weighted_size(&self) -> u64 {
+        self.clock.lock().unwrap().weight
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Cache, CacheEntry, CacheKey, Capacity, Eviction};
+    use crate::store::TEXT_CONTENT_TYPE;
+
+    #[actix_web::test]
+    async fn clock_hit_and_miss_counts() {
+        let cache = Cache::with_capacity(Capacity::Entries(8), Eviction::Clock);
+
+        assert_eq!(cache.get(&CacheKey::new("ns", "invalid")).await, None);
+        assert_eq!(cache.stats().hits, 0);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None),
+            )
+            .await;
+        assert_eq!(
+            cache
+                .get(&CacheKey::new("ns", "key_1"))
+                .await
+                .map(|entry| entry.value),
+            Some("value_1".into())
+        );
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache.remove(&CacheKey::new("ns", "key_1")).await;
+        assert_eq!(cache.get(&CacheKey::new("ns", "key_1")).await, None);
+    }
+
+    #[actix_web::test]
+    async fn clock_gives_read_entries_a_second_chance() {
+        let cache = Cache::with_capacity(Capacity::Entries(2), Eviction::Clock);
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+
+        cache
+            .insert(CacheKey::new("ns", "key_1"), entry.clone())
+            .await;
+        cache
+            .insert(CacheKey::new("ns", "key_2"), entry.clone())
+            .await;
+        cache.get(&CacheKey::new("ns", "key_1")).await;
+        cache.insert(CacheKey::new("ns", "key_3"), entry).await;
+
+        assert!(cache.contains(&CacheKey::new("ns", "key_1")));
+        assert!(!cache.contains(&CacheKey::new("ns", "key_2")));
+        assert!(cache.contains(&CacheKey::new("ns", "key_3")));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+}
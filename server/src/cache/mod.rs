@@ -0,0 +1,847 @@
+mod arc;
+mod clock;
+
+pub use arc::ArcStore;
+pub use clock::ClockStore;
+
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use moka::notification::RemovalCause;
+use moka::policy::EvictionPolicy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone)]
+pub struct KVPair {
+    pub key: String,
+    pub value: String,
+}
+
+/// Keys are cached per namespace, so that one namespace can be flushed without touching others.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub namespace: String,
+    pub key: String,
+}
+
+impl CacheKey {
+    pub fn new(namespace: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            key: key.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub value: Bytes,
+    pub content_type: String,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CacheEntry {
+    pub fn new(
+        value: impl Into<Bytes>,
+        content_type: impl Into<String>,
+        version: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            value: value.into(),
+            content_type: content_type.into(),
+            version,
+            expires_at,
+        }
+    }
+
+    /// Whether the entry has expired, for entries held outside of the cache.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// Time left until the entry expires, or `None` if it never does.
+    fn time_to_live(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|at| (at - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+    }
+}
+
+/// Approximate memory used by an entry, in bytes.
+fn weigh(key: &CacheKey, entry: &CacheEntry) -> u64 {
+    (key.namespace.len() + key.key.len() + entry.value.len() + entry.content_type.len()) as u64
+}
+
+/// Expires each entry at its own `expires_at` instead of waiting for eviction.
+struct EntryExpiry;
+
+impl moka::Expiry<CacheKey, CacheEntry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &CacheKey,
+        value: &CacheEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        value.time_to_live()
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        value: &CacheEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.time_to_live()
+    }
+}
+
+/// How writes treat the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WritePolicy {
+    /// Caches every written value.
+    #[default]
+    WriteThrough,
+    /// Only refreshes values that are already cached, so that pairs written once and rarely read
+    /// do not evict others.
+    WriteAround,
+    /// Drops written pairs from the cache, leaving the next read to cache them.
+    Invalidate,
+}
+
+impl FromStr for WritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "write_through" => Ok(WritePolicy::WriteThrough),
+            "write_around" => Ok(WritePolicy::WriteAround),
+            "invalidate" => Ok(WritePolicy::Invalidate),
+            other => Err(format!("unknown write policy: {}", other)),
+        }
+    }
+}
+
+/// Write policy of each namespace, falling back to a default one.
+#[derive(Debug, Clone, Default)]
+pub struct WritePolicies {
+    pub default: WritePolicy,
+    pub namespaces: HashMap<String, WritePolicy>,
+}
+
+impl WritePolicies {
+    pub fn get(&self, namespace: &str) -> WritePolicy {
+        self.namespaces
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Bound on the size of the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacity {
+    /// Number of entries, regardless of their size.
+    Entries(u64),
+    /// Total size of the keys and values, in bytes.
+    Bytes(u64),
+}
+
+impl Capacity {
+    /// Largest total weight of the cached entries.
+    pub fn limit(&self) -> u64 {
+        match self {
+            Capacity::Entries(entries) => *entries,
+            Capacity::Bytes(bytes) => *bytes,
+        }
+    }
+
+    /// Weight of an entry towards the limit.
+    pub fn weigh(&self, key: &CacheKey, entry: &CacheEntry) -> u64 {
+        match self {
+            Capacity::Entries(_) => 1,
+            Capacity::Bytes(_) => weigh(key, entry),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapacityUnit {
+    Entries,
+    Bytes,
+}
+
+/// Which entries make room for new ones once the cache is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Least recently used, by moka.
+    #[default]
+    Lru,
+    /// Least recently used, but only admitting entries used more often than the ones they would
+    /// evict, by moka.
+    TinyLfu,
+    /// Second chance for recently used entries, see [`ClockStore`].
+    Clock,
+    /// Adaptive replacement cache, see [`ArcStore`].
+    Arc,
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Eviction::Lru),
+            "tiny_lfu" => Ok(Eviction::TinyLfu),
+            "clock" => Ok(Eviction::Clock),
+            "arc" => Ok(Eviction::Arc),
+            other => Err(format!("unknown eviction policy: {}", other)),
+        }
+    }
+}
+
+/// Holds the entries of a [`Cache`] and decides which ones to evict. Implementations must drop
+/// entries past their `expires_at`, at the latest when they are read.
+pub trait CacheStore: fmt::Debug + Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry>;
+
+    fn insert(&self, key: CacheKey, entry: CacheEntry);
+
+    fn remove(&self, key: &CacheKey);
+
+    fn contains(&self, key: &CacheKey) -> bool;
+
+    fn clear(&self);
+
+    fn clear_namespace(&self, namespace: &str);
+
+    fn entry_count(&self) -> u64;
+
+    /// Total weight of the entries, in the unit of the capacity.
+    fn weighted_size(&self) -> u64;
+
+    /// Entries evicted to make room for others so far.
+    fn evictions(&self) -> u64;
+
+    /// Applies pending maintenance, so that counts are exact.
+    fn run_pending_tasks(&self) {}
+}
+
+/// Entries kept by moka, evicting by LRU or TinyLFU.
+#[derive(Debug)]
+struct MokaStore {
+    map: moka::sync::Cache<CacheKey, CacheEntry>,
+    evictions: Arc<AtomicU64>,
+}
+
+impl MokaStore {
+    fn new(capacity: Capacity, policy: EvictionPolicy) -> Self {
+        let builder = moka::sync::Cache::builder()
+            .max_capacity(capacity.limit())
+            .eviction_policy(policy)
+            .expire_after(EntryExpiry)
+            .support_invalidation_closures();
+        let evictions = Arc::new(AtomicU64::new(0));
+        let evicted = evictions.clone();
+        let builder = builder.eviction_listener(move |_, _, cause| {
+            if cause == RemovalCause::Size {
+                evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let map = match capacity {
+            Capacity::Entries(_) => builder.build(),
+            Capacity::Bytes(_) => builder
+                .weigher(|key, entry| weigh(key, entry).try_into().unwrap_or(u32::MAX))
+                .build(),
+        };
+
+        Self { map, evictions }
+    }
+}
+
+impl CacheStore for MokaStore {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.map.get(key)
+    }
+
+    fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&self, key: &CacheKey) {
+        self.map.invalidate(key);
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn clear(&self) {
+        self.map.invalidate_all();
+    }
+
+    fn clear_namespace(&self, namespace: &str) {
+        let namespace = namespace.to_string();
+        // only fails if invalidation closures are not enabled on the builder
+        self.map
+            .invalidate_entries_if(move |key, _| key.namespace == namespace)
+            .expect("invalidation closures are supported");
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.map.entry_count()
+    }
+
+    fn weighted_size(&self) -> u64 {
+        self.map.weighted_size()
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    fn run_pending_tasks(&self) {
+        self.map.run_pending_tasks();
+    }
+}
+
+/// Outcome of loading a missing entry, shared with every caller waiting for it.
+pub type Loaded = Result<Option<CacheEntry>, Arc<sqlx::Error>>;
+
+/// Loads in progress, by key.
+type Inflight = Mutex<HashMap<CacheKey, broadcast::Sender<Loaded>>>;
+
+/// Forgets a load once its leader is done with it, even if the leader is cancelled, so that the
+/// callers waiting for it load the entry themselves.
+struct LoadGuard<'a> {
+    inflight: &'a Inflight,
+    key: &'a CacheKey,
+    finished: bool,
+}
+
+impl LoadGuard<'_> {
+    /// Hands the outcome of the load to the callers waiting for it.
+    fn finish(mut self, loaded: &Loaded) {
+        // sent while holding the lock, so that nobody subscribes once it has been sent
+        if let Some(sender) = self.inflight.lock().unwrap().remove(self.key) {
+            let _ = sender.send(loaded.clone());
+        }
+        self.finished = true;
+    }
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.inflight.lock().unwrap().remove(self.key);
+        }
+    }
+}
+
+/// Remembers keys found missing for a short while, so that repeated reads of a missing key do not
+/// all reach the database.
+#[derive(Debug)]
+struct NegativeCache {
+    keys: moka::sync::Cache<CacheKey, ()>,
+    /// Bumped whenever keys may have been written, so that a load that found a key missing before
+    /// the write does not remember it as missing afterwards.
+    epoch: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    capacity: Capacity,
+    eviction: Eviction,
+    /// Entries larger than this many bytes are never cached.
+    max_entry_bytes: Option<u64>,
+    write_policies: Arc<WritePolicies>,
+    store: Arc<dyn CacheStore>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    insertions: Arc<AtomicU64>,
+    inflight: Arc<Inflight>,
+    coalesced: Arc<AtomicU64>,
+    negative: Option<Arc<NegativeCache>>,
+    negative_hits: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub capacity: u64,
+    pub capacity_unit: CapacityUnit,
+    pub eviction: Eviction,
+    pub entries: u64,
+    /// size of the cached entries, in the unit of `capacity`
+    pub weighted_size: u64,
+    pub max_entry_bytes: Option<u64>,
+    pub hits: u64,
+    pub misses: u64,
+    pub insertions: u64,
+    pub evictions: u64,
+    /// misses that waited for a load already in progress instead of loading the entry again
+    pub coalesced: u64,
+    /// misses answered by the negative cache
+    pub negative_hits: u64,
+    /// keys remembered as missing, absent without a negative cache
+    pub negative_entries: Option<u64>,
+    pub write_policy: WritePolicy,
+    /// namespaces that do not follow `write_policy`
+    pub namespace_write_policies: BTreeMap<String, WritePolicy>,
+}
+
+impl Cache {
+    /// Creates an LRU cache holding up to `capacity` entries.
+    pub fn new(capacity: u64) -> Self {
+        Self::with_capacity(Capacity::Entries(capacity), Eviction::Lru)
+    }
+
+    pub fn with_capacity(capacity: Capacity, eviction: Eviction) -> Self {
+        let store: Arc<dyn CacheStore> = match eviction {
+            Eviction::Lru => Arc::new(MokaStore::new(capacity, EvictionPolicy::lru())),
+            Eviction::TinyLfu => Arc::new(MokaStore::new(capacity, EvictionPolicy::tiny_lfu())),
+            Eviction::Clock => Arc::new(ClockStore::new(capacity)),
+            Eviction::Arc => Arc::new(ArcStore::new(capacity)),
+        };
+
+        Self {
+            capacity,
+            eviction,
+            max_entry_bytes: None,
+            write_policies: Arc::default(),
+            store,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            insertions: Arc::new(AtomicU64::new(0)),
+            inflight: Arc::default(),
+            coalesced: Arc::new(AtomicU64::new(0)),
+            negative: None,
+            negative_hits: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Remembers up to `capacity` missing keys for `ttl` each.
+    pub fn with_negative_cache(mut self, capacity: u64, ttl: Duration) -> Self {
+        self.negative = Some(Arc::new(NegativeCache {
+            keys: moka::sync::Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .support_invalidation_closures()
+                .build(),
+            epoch: AtomicU64::new(0),
+        }));
+        self
+    }
+
+    pub fn with_max_entry_bytes(mut self, max_entry_bytes: Option<u64>) -> Self {
+        self.max_entry_bytes = max_entry_bytes;
+        self
+    }
+
+    pub fn with_write_policies(mut self, write_policies: WritePolicies) -> Self {
+        self.write_policies = Arc::new(write_policies);
+        self
+    }
+
+    pub async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        match self.store.get(key) {
+            Some(entry) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Like [`Cache::get`], but loads a missing entry with `load` and caches it. Concurrent misses
+    /// of the same key wait for a single load, and all of them get its outcome. Errors are not
+    /// cached, and missing entries only in the negative cache, if enabled.
+    pub async fn get_or_load<F>(&self, key: &CacheKey, load: F) -> Loaded
+    where
+        F: Future<Output = Result<Option<CacheEntry>, sqlx::Error>>,
+    {
+        if let Some(entry) = self.get(key).await {
+            return Ok(Some(entry));
+        }
+        if let Some(negative) = &self.negative
+            && negative.keys.contains_key(key)
+        {
+            self.negative_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+
+        let waiting = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    inflight.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+        if let Some(mut receiver) = waiting {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            // only fails if the load was cancelled
+            if let Ok(loaded) = receiver.recv().await {
+                return loaded;
+            }
+            return self.load(key, load).await;
+        }
+
+        let guard = LoadGuard {
+            inflight: &self.inflight,
+            key,
+            finished: false,
+        };
+        let loaded = self.load(key, load).await;
+        guard.finish(&loaded);
+
+        loaded
+    }
+
+    async fn load<F>(&self, key: &CacheKey, load: F) -> Loaded
+    where
+        F: Future<Output = Result<Option<CacheEntry>, sqlx::Error>>,
+    {
+        let epoch = self
+            .negative
+            .as_ref()
+            .map(|negative| negative.epoch.load(Ordering::Acquire));
+        let loaded = load.await.map_err(Arc::new);
+        match &loaded {
+            Ok(Some(entry)) => self.insert(key.clone(), entry.clone()).await,
+            Ok(None) => {
+                if let Some(negative) = &self.negative
+                    && epoch == Some(negative.epoch.load(Ordering::Acquire))
+                {
+                    negative.keys.insert(key.clone(), ());
+                }
+            }
+            Err(_) => {}
+        }
+
+        loaded
+    }
+
+    /// Caches `entry`, unless it is larger than the per-entry cap, in which case any previous
+    /// value is dropped instead.
+    pub async fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        self.forget_missing(Some(&key));
+        if self
+            .max_entry_bytes
+            .is_some_and(|max| weigh(&key, &entry) > max)
+        {
+            self.store.remove(&key);
+            return;
+        }
+
+        self.store.insert(key, entry);
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Updates the cache after `key` was written, following the write policy of its namespace.
+    pub async fn write(&self, key: CacheKey, entry: CacheEntry) {
+        self.forget_missing(Some(&key));
+        match self.write_policies.get(&key.namespace) {
+            WritePolicy::WriteThrough => self.insert(key, entry).await,
+            // a cached value that is not refreshed would be stale
+            WritePolicy::WriteAround if self.contains(&key) => self.insert(key, entry).await,
+            WritePolicy::WriteAround => {}
+            WritePolicy::Invalidate => self.remove(&key).await,
+        }
+    }
+
+    /// Whether `key` is cached, without counting towards hits or misses.
+    pub fn contains(&self, key: &CacheKey) -> bool {
+        self.store.contains(key)
+    }
+
+    /// Drops `key`, whether it is cached or remembered as missing.
+    pub async fn remove(&self, key: &CacheKey) {
+        self.forget_missing(Some(key));
+        self.store.remove(key);
+    }
+
+    /// Drops `key` (or every key, if `None`) from the negative cache, as it may have been written.
+    fn forget_missing(&self, key: Option<&CacheKey>) {
+        let Some(negative) = &self.negative else {
+            return;
+        };
+
+        negative.epoch.fetch_add(1, Ordering::AcqRel);
+        match key {
+            Some(key) => negative.keys.invalidate(key),
+            None => negative.keys.invalidate_all(),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.store.entry_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let capacity_unit = match self.capacity {
+            Capacity::Entries(_) => CapacityUnit::Entries,
+            Capacity::Bytes(_) => CapacityUnit::Bytes,
+        };
+
+        CacheStats {
+            capacity: self.capacity.limit(),
+            capacity_unit,
+            eviction: self.eviction,
+            entries: self.store.entry_count(),
+            weighted_size: self.store.weighted_size(),
+            max_entry_bytes: self.max_entry_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.store.evictions(),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            negative_entries: self
+                .negative
+                .as_ref()
+                .map(|negative| negative.keys.entry_count()),
+            write_policy: self.write_policies.default,
+            namespace_write_policies: self
+                .write_policies
+                .namespaces
+                .iter()
+                .map(|(namespace, policy)| (namespace.clone(), *policy))
+                .collect(),
+        }
+    }
+
+    pub fn flush(&self) {
+        self.forget_missing(None);
+        self.store.clear();
+    }
+
+    /// Invalidates every entry of `namespace`, leaving other namespaces cached.
+    pub fn flush_namespace(&self, namespace: &str) {
+        // keys of other namespaces are forgotten as well, as the negative cache is short-lived
+        self.forget_missing(None);
+        self.store.clear_namespace(namespace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheEntry, CacheKey, Capacity, Eviction, WritePolicies, WritePolicy};
+    use crate::store::TEXT_CONTENT_TYPE;
+    use chrono::{TimeDelta, Utc};
+    use futures_util::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn cache_hit_and_miss_counts() {
+        let cache = Cache::new(8);
+
+        assert_eq!(cache.get(&CacheKey::new("ns", "invalid")).await, None);
+        assert_eq!(cache.stats().hits, 0);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None),
+            )
+            .await;
+        assert_eq!(
+            cache
+                .get(&CacheKey::new("ns", "key_1"))
+                .await
+                .map(|entry| entry.value),
+            Some("value_1".into())
+        );
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+
+        cache.remove(&CacheKey::new("ns", "key_1")).await;
+        assert_eq!(cache.get(&CacheKey::new("ns", "key_1")).await, None);
+    }
+
+    #[actix_web::test]
+    async fn cache_coalesces_concurrent_misses() {
+        let cache = Cache::new(8);
+        let loads = AtomicUsize::new(0);
+        let load = |loaded: Result<Option<CacheEntry>, sqlx::Error>| {
+            let loads = &loads;
+            async move {
+                loads.fetch_add(1, Ordering::Relaxed);
+                actix_rt::time::sleep(Duration::from_millis(50)).await;
+                loaded
+            }
+        };
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+        let key = CacheKey::new("ns", "key_1");
+
+        let loaded =
+            join_all((0..4).map(|_| cache.get_or_load(&key, load(Ok(Some(entry.clone())))))).await;
+        assert!(loaded
+            .iter()
+            .all(|loaded| loaded.as_ref().unwrap().as_ref() == Some(&entry)));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats().coalesced, 3);
+        assert!(cache.contains(&key));
+
+        // missing entries and errors reach every caller, but are not cached
+        let key = CacheKey::new("ns", "key_2");
+        let loaded = join_all((0..4).map(|_| cache.get_or_load(&key, load(Ok(None))))).await;
+        assert!(loaded.iter().all(|loaded| matches!(loaded, Ok(None))));
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+
+        let loaded =
+            join_all((0..4).map(|_| cache.get_or_load(&key, load(Err(sqlx::Error::PoolTimedOut)))))
+                .await;
+        assert!(loaded.iter().all(|loaded| loaded.is_err()));
+        assert_eq!(loads.load(Ordering::Relaxed), 3);
+        assert!(!cache.contains(&key));
+        assert_eq!(cache.stats().coalesced, 9);
+    }
+
+    #[actix_web::test]
+    async fn cache_remembers_missing_keys_until_written() {
+        let cache = Cache::new(8).with_negative_cache(8, Duration::from_secs(60));
+        let key = CacheKey::new("ns", "key_1");
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+
+        assert!(matches!(
+            cache.get_or_load(&key, async { Ok(None) }).await,
+            Ok(None)
+        ));
+        let loaded = cache
+            .get_or_load(&key, async { Ok(Some(entry.clone())) })
+            .await;
+        assert!(matches!(loaded, Ok(None)));
+        assert_eq!(cache.stats().negative_hits, 1);
+        assert_eq!(cache.stats().misses, 2);
+
+        cache.write(key.clone(), entry.clone()).await;
+        let loaded = cache.get_or_load(&key, async { Ok(None) }).await;
+        assert_eq!(loaded.unwrap(), Some(entry));
+        assert_eq!(cache.stats().negative_hits, 1);
+    }
+
+    #[actix_web::test]
+    async fn cache_follows_write_policies() {
+        let cache = Cache::new(8).with_write_policies(WritePolicies {
+            default: WritePolicy::WriteAround,
+            namespaces: [("hot".to_string(), WritePolicy::WriteThrough)]
+                .into_iter()
+                .collect(),
+        });
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+
+        cache
+            .write(CacheKey::new("hot", "key_1"), entry.clone())
+            .await;
+        assert!(cache.contains(&CacheKey::new("hot", "key_1")));
+
+        // write-around only refreshes pairs that are already cached
+        cache
+            .write(CacheKey::new("ns", "key_1"), entry.clone())
+            .await;
+        assert!(!cache.contains(&CacheKey::new("ns", "key_1")));
+
+        cache.insert(CacheKey::new("ns", "key_1"), entry).await;
+        let updated = CacheEntry::new("value_2", TEXT_CONTENT_TYPE, 2, None);
+        cache
+            .write(CacheKey::new("ns", "key_1"), updated.clone())
+            .await;
+        assert_eq!(
+            cache.get(&CacheKey::new("ns", "key_1")).await,
+            Some(updated)
+        );
+
+        let cache = Cache::new(8).with_write_policies(WritePolicies {
+            default: WritePolicy::Invalidate,
+            ..WritePolicies::default()
+        });
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+        cache
+            .insert(CacheKey::new("ns", "key_1"), entry.clone())
+            .await;
+        cache.write(CacheKey::new("ns", "key_1"), entry).await;
+        assert!(!cache.contains(&CacheKey::new("ns", "key_1")));
+        assert_eq!(cache.stats().write_policy, WritePolicy::Invalidate);
+    }
+
+    #[actix_web::test]
+    async fn cache_weighs_entries_by_size() {
+        let cache = Cache::with_capacity(Capacity::Bytes(1024), Eviction::Lru)
+            .with_max_entry_bytes(Some(64));
+
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None),
+            )
+            .await;
+        cache.store.run_pending_tasks();
+        assert_eq!(
+            cache.stats().weighted_size,
+            ("ns".len() + "key_1".len() + "value_1".len() + TEXT_CONTENT_TYPE.len()) as u64
+        );
+
+        // oversized values are not cached, and do not leave a stale value behind
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new(vec![0; 64], TEXT_CONTENT_TYPE, 2, None),
+            )
+            .await;
+        assert_eq!(cache.get(&CacheKey::new("ns", "key_1")).await, None);
+    }
+
+    #[actix_web::test]
+    async fn cache_entries_expire() {
+        let cache = Cache::new(8);
+        let expires_at = Utc::now() + TimeDelta::milliseconds(100);
+
+        cache
+            .insert(
+                CacheKey::new("ns", "key_1"),
+                CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, Some(expires_at)),
+            )
+            .await;
+        assert!(cache.get(&CacheKey::new("ns", "key_1")).await.is_some());
+
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(cache.get(&CacheKey::new("ns", "key_1")).await, None);
+    }
+
+    #[actix_web::test]
+    async fn cache_flushes_single_namespace() {
+        let cache = Cache::new(8);
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+
+        cache
+            .insert(CacheKey::new("ns_1", "key_1"), entry.clone())
+            .await;
+        cache.insert(CacheKey::new("ns_2", "key_1"), entry).await;
+
+        cache.flush_namespace("ns_1");
+        assert_eq!(cache.get(&CacheKey::new("ns_1", "key_1")).await, None);
+        assert!(cache.get(&CacheKey::new("ns_2", "key_1")).await.is_some());
+    }
+}
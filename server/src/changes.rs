@@ -0,0 +1,523 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+
+/// Advisory lock held in shared mode by writes to `kv_changes` until they commit, from before they
+/// draw their sequence numbers. Writes do not wait for each other; readers take it exclusively,
+/// waiting for the writes in progress, to find the sequence number up to which the log is
+/// settled, see [`latest_seq`].
+const LOG_LOCK: i64 = 0x6b76_6368_616e_6765;
+
+/// Changes fetched from the log at once.
+pub const PAGE_SIZE: i64 = 1000;
+
+/// Batches buffered per subscriber before it lags and has to catch up from the database.
+const FEED_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Put,
+    Delete,
+}
+
+/// A single entry of the change log.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub seq: i64,
+    pub namespace: String,
+    pub key: String,
+    pub op: Op,
+    /// `None` for deletes.
+    pub version: Option<i64>,
+    pub value: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Selects the changes of a namespace, optionally narrowed to a single key or a key prefix.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// All namespaces if `None`.
+    pub namespace: Option<String>,
+    pub key: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, change: &Change) -> bool {
+        self.namespace
+            .as_ref()
+            .is_none_or(|namespace| *namespace == change.namespace)
+            && self.key.as_ref().is_none_or(|key| *key == change.key)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| change.key.starts_with(prefix.as_str()))
+    }
+}
+
+/// Logs a value written at `version`.
+pub async fn record_put(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    version: i64,
+    value: &[u8],
+    content_type: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+INSERT INTO kv_changes (namespace, key, op, version, value, content_type)
+SELECT $2, $3, 'put', $4, $5, $6
+FROM pg_advisory_xact_lock_shared($1)
+        "#,
+        LOG_LOCK,
+        namespace,
+        key,
+        version,
+        value,
+        content_type
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Logs the deletion of a pair.
+pub async fn record_delete(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+INSERT INTO kv_changes (namespace, key, op)
+SELECT $2, $3, 'delete'
+FROM pg_advisory_xact_lock_shared($1)
+        "#,
+        LOG_LOCK,
+        namespace,
+        key
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Logs the deletion of every live pair of `namespace`; must run before the pairs are deleted.
+pub async fn record_flush(executor: impl PgExecutor<'_>, namespace: &str) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+WITH locked AS MATERIALIZED (SELECT pg_advisory_xact_lock_shared($1))
+INSERT INTO kv_changes (namespace, key, op)
+SELECT namespace, key, 'delete'
+FROM locked, kv_store
+WHERE namespace = $2
+  AND (expires_at IS NULL OR expires_at > NOW())
+ORDER BY key
+        "#,
+        LOG_LOCK,
+        namespace
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Sequence number up to which every change is committed (or rolled back), or 0 if there never
+/// was one. Changes up to it never appear later, so readers must not read past it.
+pub async fn latest_seq(executor: impl PgExecutor<'_>) -> sqlx::Result<i64> {
+    // sequence numbers drawn so far all belong to writes that are done once the lock is acquired;
+    // the lock is released at the end of the statement
+    sqlx::query_scalar!(
+        r#"
+WITH locked AS MATERIALIZED (SELECT pg_advisory_xact_lock($1))
+SELECT COALESCE(pg_sequence_last_value('kv_changes_seq_seq'), 0) AS "seq!"
+FROM locked
+        "#,
+        LOG_LOCK
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Sequence number up to which the log has been compacted; changes up to it are gone.
+pub async fn compacted_through(executor: impl PgExecutor<'_>) -> sqlx::Result<i64> {
+    sqlx::query_scalar!("SELECT compacted_through FROM kv_changes_compaction")
+        .fetch_one(executor)
+        .await
+}
+
+/// Checkpoint of a consumer of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Consumer {
+    pub name: String,
+    /// Every change up to this sequence number has been processed.
+    pub seq: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Moves the checkpoint of a consumer forward to `seq`, registering it if needed. A checkpoint
+/// never moves backwards.
+pub async fn acknowledge(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    seq: i64,
+) -> sqlx::Result<Consumer> {
+    sqlx::query_as!(
+        Consumer,
+        r#"
+INSERT INTO kv_change_consumers (name, seq)
+VALUES ($1, $2)
+ON CONFLICT (name)
+DO UPDATE
+SET seq        = GREATEST(kv_change_consumers.seq, EXCLUDED.seq),
+    updated_at = NOW()
+RETURNING name, seq, updated_at
+        "#,
+        name,
+        seq
+    )
+    .fetch_one(executor)
+    .await
+}
+
+pub async fn consumers(executor: impl PgExecutor<'_>) -> sqlx::Result<Vec<Consumer>> {
+    sqlx::query_as!(
+        Consumer,
+        "SELECT name, seq, updated_at FROM kv_change_consumers ORDER BY name"
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Unregisters a consumer, so that it no longer holds back compaction. Returns `false` if it did
+/// not exist.
+pub async fn remove_consumer(executor: impl PgExecutor<'_>, name: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query!("DELETE FROM kv_change_consumers WHERE name = $1", name)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes at most `batch_size` sequence numbers worth of changes acknowledged by every consumer,
+/// or older than `retention` while no consumer is registered. Returns how many were removed, or
+/// `None` once the log is compacted as far as it can be: the compacted sequence number may move
+/// forward over gaps in the log without removing anything.
+pub async fn compact(
+    db_pool: &PgPool,
+    batch_size: i64,
+    retention: Duration,
+) -> sqlx::Result<Option<u64>> {
+    // changes past it may still be joined by earlier ones, which must not be skipped over
+    let latest = latest_seq(db_pool).await?;
+    let mut tx = db_pool.begin().await?;
+
+    let from =
+        sqlx::query_scalar!("SELECT compacted_through FROM kv_changes_compaction FOR UPDATE")
+            .fetch_one(&mut *tx)
+            .await?;
+    let checkpoint = match sqlx::query_scalar!("SELECT MIN(seq) FROM kv_change_consumers")
+        .fetch_one(&mut *tx)
+        .await?
+    {
+        Some(checkpoint) => checkpoint,
+        None => sqlx::query_scalar!(
+            r#"
+SELECT MIN(seq) - 1
+FROM kv_changes
+WHERE seq > $1
+  AND changed_at > NOW() - $2::BIGINT * INTERVAL '1 second'
+            "#,
+            from,
+            retention.as_secs() as i64
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .unwrap_or(latest),
+    };
+    let to = checkpoint.min(latest).min(from.saturating_add(batch_size));
+    if to <= from {
+        return Ok(None);
+    }
+
+    let result = sqlx::query!(
+        "DELETE FROM kv_changes WHERE seq > $1 AND seq <= $2",
+        from,
+        to
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE kv_changes_compaction SET compacted_through = $1",
+        to
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(result.rows_affected()))
+}
+
+/// Lists up to `limit` changes matching `filter` with a sequence number in `(after, up_to]`, in
+/// order.
+pub async fn list(
+    executor: impl PgExecutor<'_>,
+    filter: &Filter,
+    after: i64,
+    up_to: Option<i64>,
+    limit: i64,
+) -> sqlx::Result<Vec<Change>> {
+    sqlx::query_as!(
+        Change,
+        r#"
+SELECT seq, namespace, key, op AS "op: Op", version, value, content_type, changed_at
+FROM kv_changes
+WHERE seq > $1
+  AND ($2::BIGINT IS NULL OR seq <= $2)
+  AND ($3::TEXT IS NULL OR namespace = $3)
+  AND ($4::TEXT IS NULL OR key = $4)
+  AND ($5::TEXT IS NULL OR starts_with(key, $5))
+ORDER BY seq
+LIMIT $6
+        "#,
+        after,
+        up_to,
+        filter.namespace,
+        filter.key,
+        filter.prefix,
+        limit
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Every change with a sequence number in `(after, up_to]`.
+#[derive(Debug)]
+pub struct Batch {
+    pub after: i64,
+    pub up_to: i64,
+    pub changes: Vec<Change>,
+}
+
+/// Fans changes out to the watchers of this instance, so that they share a single reader of the
+/// log instead of each polling it.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<Arc<Batch>>,
+    /// Latest sequence number published, `None` until the first poll.
+    cursor: Arc<Mutex<Option<i64>>>,
+    /// Holds a permit while a poll is requested.
+    requested: Arc<Notify>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+            cursor: Arc::new(Mutex::new(None)),
+            requested: Arc::default(),
+        }
+    }
+
+    /// Asks for a poll. Requests made before the poll starts are served by it.
+    pub fn request_poll(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Waits until a poll is requested.
+    pub async fn poll_requested(&self) {
+        self.requested.notified().await;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Batch>> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes the changes committed since the last poll. Must not be called concurrently.
+    pub async fn poll(&self, db_pool: &PgPool) -> sqlx::Result<()> {
+        // watchers catch up from the database with whatever they did not receive, so the log is
+        // only read while somebody is watching
+        if self.sender.receiver_count() == 0 {
+            *self.cursor.lock().unwrap() = None;
+            return Ok(());
+        }
+
+        let cursor = *self.cursor.lock().unwrap();
+        let mut cursor = match cursor {
+            Some(cursor) => cursor,
+            // nobody can be waiting on changes from before the first poll, they are read from the
+            // database on subscription
+            None => latest_seq(db_pool).await?,
+        };
+
+        let latest = latest_seq(db_pool).await?;
+        while cursor < latest {
+            let changes =
+                list(db_pool, &Filter::default(), cursor, Some(latest), PAGE_SIZE).await?;
+            let full = changes.len() as i64 == PAGE_SIZE;
+            let up_to = match changes.last() {
+                Some(last) if full => last.seq,
+                _ => latest,
+            };
+
+            let batch = Batch {
+                after: cursor,
+                up_to,
+                changes,
+            };
+            cursor = batch.up_to;
+            // fails only if nobody is subscribed
+            let _ = self.sender.send(Arc::new(batch));
+        }
+
+        *self.cursor.lock().unwrap() = Some(cursor);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        acknowledge, compact, compacted_through, latest_seq, list, record_delete, record_put,
+        ChangeFeed, Filter, Op,
+    };
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn feed_publishes_new_changes(pool: PgPool) -> sqlx::Result<()> {
+        let feed = ChangeFeed::new();
+        let mut receiver = feed.subscribe();
+
+        // changes from before the first poll are not published
+        record_put(&pool, "ns", "key_1", 1, b"value_1", "text/plain").await?;
+        feed.poll(&pool).await?;
+        assert!(receiver.try_recv().is_err());
+
+        record_put(&pool, "ns", "key_1", 2, b"value_2", "text/plain").await?;
+        record_delete(&pool, "ns", "key_1").await?;
+        feed.poll(&pool).await?;
+
+        let batch = receiver.try_recv().unwrap();
+        let ops: Vec<_> = batch.changes.iter().map(|change| change.op).collect();
+        assert_eq!(ops, vec![Op::Put, Op::Delete]);
+        assert_eq!(batch.up_to, batch.changes[1].seq);
+        assert!(batch.after < batch.changes[0].seq);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn poll_requests_are_merged() {
+        let feed = ChangeFeed::new();
+        for _ in 0..3 {
+            feed.request_poll();
+        }
+
+        feed.poll_requested().await;
+        let again = actix_rt::time::timeout(Duration::from_millis(50), feed.poll_requested());
+        assert!(again.await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn compacts_up_to_lowest_checkpoint(pool: PgPool) -> sqlx::Result<()> {
+        for version in 1..=3 {
+            record_put(&pool, "ns", "key_1", version, b"value_1", "text/plain").await?;
+        }
+        let latest = latest_seq(&pool).await?;
+
+        acknowledge(&pool, "consumer_1", latest).await?;
+        acknowledge(&pool, "consumer_2", latest - 1).await?;
+        // checkpoints never move backwards
+        assert_eq!(acknowledge(&pool, "consumer_1", 0).await?.seq, latest);
+
+        // retention does not apply while there are consumers
+        assert_eq!(compact(&pool, 100, Duration::ZERO).await?, Some(2));
+        assert_eq!(compacted_through(&pool).await?, latest - 1);
+        assert_eq!(compact(&pool, 100, Duration::ZERO).await?, None);
+
+        // the latest sequence number survives the compaction of the whole log
+        acknowledge(&pool, "consumer_2", latest).await?;
+        assert_eq!(compact(&pool, 100, Duration::ZERO).await?, Some(1));
+        assert_eq!(latest_seq(&pool).await?, latest);
+
+        // compaction moves on over gaps in the log
+        sqlx::query("SELECT setval('kv_changes_seq_seq', $1)")
+            .bind(latest + 25)
+            .execute(&pool)
+            .await?;
+        record_put(&pool, "ns", "key_1", 4, b"value_4", "text/plain").await?;
+        acknowledge(&pool, "consumer_1", latest + 26).await?;
+        acknowledge(&pool, "consumer_2", latest + 26).await?;
+        assert_eq!(compact(&pool, 10, Duration::ZERO).await?, Some(0));
+        assert_eq!(compact(&pool, 10, Duration::ZERO).await?, Some(0));
+        assert_eq!(compact(&pool, 10, Duration::ZERO).await?, Some(1));
+        assert_eq!(compacted_through(&pool).await?, latest + 26);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn compacts_old_changes_without_consumers(pool: PgPool) -> sqlx::Result<()> {
+        for version in 1..=3 {
+            record_put(&pool, "ns", "key_1", version, b"value_1", "text/plain").await?;
+        }
+        let latest = latest_seq(&pool).await?;
+        sqlx::query("UPDATE kv_changes SET changed_at = NOW() - INTERVAL '2 hours' WHERE seq < $1")
+            .bind(latest)
+            .execute(&pool)
+            .await?;
+
+        let retention = Duration::from_secs(60 * 60);
+        assert_eq!(compact(&pool, 100, retention).await?, Some(2));
+        assert_eq!(compacted_through(&pool).await?, latest - 1);
+        assert_eq!(compact(&pool, 100, retention).await?, None);
+
+        // registering a consumer holds back compaction again
+        acknowledge(&pool, "consumer_1", latest - 1).await?;
+        assert_eq!(compact(&pool, 100, Duration::ZERO).await?, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn readers_wait_for_writes_in_progress(pool: PgPool) -> sqlx::Result<()> {
+        let mut tx = pool.begin().await?;
+        record_put(&mut *tx, "ns", "key_1", 1, b"value_1", "text/plain").await?;
+
+        // other writes go ahead, committing a later sequence number first
+        record_put(&pool, "ns", "key_2", 1, b"value_2", "text/plain").await?;
+
+        // but the log is not settled past the first one until it commits
+        let reader = pool.clone();
+        let latest = tokio::spawn(async move { latest_seq(&reader).await });
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(!latest.is_finished());
+
+        tx.commit().await?;
+        let latest = latest.await.unwrap()?;
+        let changes = list(&pool, &Filter::default(), 0, Some(latest), 10).await?;
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1].seq, latest);
+
+        Ok(())
+    }
+}
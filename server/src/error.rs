@@ -1,6 +1,10 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use serde::Serialize;
 use sqlx;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -11,12 +15,30 @@ pub enum AppError {
     NotFound(String),
     #[error("bad request: {0}")]
     BadRequest(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// Carries the seconds to wait before retrying.
+    #[error("too many requests: retry after {0}s")]
+    TooManyRequests(u64),
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("gone: {0}")]
+    Gone(String),
     #[error("serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
     #[error("internal server error: {0}")]
     Internal(String),
 }
 
+/// Database errors shared by coalesced cache loads.
+impl From<Arc<sqlx::Error>> for AppError {
+    fn from(err: Arc<sqlx::Error>) -> Self {
+        AppError::Internal(format!("database error: {}", err))
+    }
+}
+
 #[derive(Serialize)]
 struct ErrorResponse {
     error: String,
@@ -28,13 +50,29 @@ impl ResponseError for AppError {
             AppError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            AppError::Gone(_) => StatusCode::GONE,
             AppError::Serialization(_) => StatusCode::BAD_REQUEST,
             AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(ErrorResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        match self {
+            AppError::Unauthorized(_) => {
+                res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+            }
+            AppError::TooManyRequests(retry_after) => {
+                res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            }
+            _ => {}
+        }
+
+        res.json(ErrorResponse {
             error: self.to_string(),
         })
     }
@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::Migrator;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Migrations embedded in the binary, applied on startup.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Time allowed for each dependency check unless configured otherwise.
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long dependency checks are reused for, so that probes do not each query the database.
+const CHECK_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// Reported for failed checks, whose details are logged instead of being exposed.
+const CHECK_FAILED: &str = "check failed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Component {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Component {
+    fn up(latency: Option<Duration>) -> Self {
+        Self {
+            status: Status::Up,
+            latency_ms: latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            error: None,
+        }
+    }
+
+    fn down(latency: Option<Duration>, error: impl Into<String>) -> Self {
+        Self {
+            status: Status::Down,
+            latency_ms: latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            error: Some(error.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub status: Status,
+    pub components: BTreeMap<String, Component>,
+}
+
+/// Dependencies as of the last check.
+#[derive(Debug)]
+struct Checked {
+    at: Instant,
+    components: BTreeMap<String, Component>,
+}
+
+/// Whether this instance should receive traffic.
+#[derive(Debug, Clone)]
+pub struct Health {
+    check_timeout: Duration,
+    draining: Arc<AtomicBool>,
+    checked: Arc<Mutex<Option<Checked>>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHECK_TIMEOUT)
+    }
+}
+
+impl Health {
+    pub fn new(check_timeout: Duration) -> Self {
+        Self {
+            check_timeout,
+            draining: Arc::default(),
+            checked: Arc::default(),
+        }
+    }
+
+    /// Marks this instance as shutting down, failing readiness from now on.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Checks every dependency, each within the check timeout.
+    pub async fn readiness(&self, db_pool: &PgPool) -> Readiness {
+        let mut components = self.dependencies(db_pool).await;
+        components.insert(
+            "shutdown".to_string(),
+            if self.is_draining() {
+                Component::down(None, "draining")
+            } else {
+                Component::up(None)
+            },
+        );
+
+        let status = if components
+            .values()
+            .all(|component| component.status == Status::Up)
+        {
+            Status::Up
+        } else {
+            Status::Down
+        };
+
+        Readiness { status, components }
+    }
+
+    /// Checks the dependencies at most once per [`CHECK_CACHE_TTL`], concurrent callers waiting
+    /// for the check in progress.
+    async fn dependencies(&self, db_pool: &PgPool) -> BTreeMap<String, Component> {
+        let mut checked = self.checked.lock().await;
+        if let Some(checked) = &*checked
+            && checked.at.elapsed() < CHECK_CACHE_TTL
+        {
+            return checked.components.clone();
+        }
+
+        let mut components = BTreeMap::new();
+        components.insert(
+            "database".to_string(),
+            self.check("database", async {
+                sqlx::query_scalar!(r#"SELECT 1 AS "one!""#)
+                    .fetch_one(db_pool)
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            })
+            .await,
+        );
+        components.insert(
+            "migrations".to_string(),
+            self.check("migrations", check_migrations(db_pool)).await,
+        );
+
+        *checked = Some(Checked {
+            at: Instant::now(),
+            components: components.clone(),
+        });
+        components
+    }
+
+    async fn check(
+        &self,
+        name: &str,
+        check: impl Future<Output = Result<(), String>>,
+    ) -> Component {
+        let started = Instant::now();
+        match actix_rt::time::timeout(self.check_timeout, check).await {
+            Ok(Ok(())) => Component::up(Some(started.elapsed())),
+            Ok(Err(err)) => {
+                tracing::warn!("{} check failed: {}", name, err);
+                Component::down(Some(started.elapsed()), CHECK_FAILED)
+            }
+            Err(_) => Component::down(
+                Some(started.elapsed()),
+                format!("timed out after {:?}", self.check_timeout),
+            ),
+        }
+    }
+}
+
+/// Fails unless every migration embedded in the binary has been applied successfully.
+async fn check_migrations(db_pool: &PgPool) -> Result<(), String> {
+    let applied: HashSet<i64> =
+        sqlx::query_scalar!("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(db_pool)
+            .await
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .collect();
+
+    let pending = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .count();
+    if pending > 0 {
+        return Err(format!("{} migrations pending", pending));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgExecutor;
+use std::time::Duration;
+
+/// How long recorded versions are kept. Both limits apply when set; with neither, history grows
+/// without bound.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Versions kept per key, newest first.
+    pub max_versions: Option<i64>,
+    /// Versions superseded longer ago than this are dropped, so that reads `as_of` any time within
+    /// the window still find the value live at that time.
+    pub max_age: Option<Duration>,
+}
+
+/// A recorded version of a pair.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub version: i64,
+    /// `None` if the pair was deleted at this version.
+    pub value: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Records a value written at `version`.
+pub async fn record_write(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    version: i64,
+    value: &[u8],
+    content_type: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+INSERT INTO kv_history (namespace, key, version, value, content_type, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        namespace,
+        key,
+        version,
+        value,
+        content_type,
+        expires_at
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Records the deletion of a pair under a fresh version.
+pub async fn record_delete(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+INSERT INTO kv_history (namespace, key, version)
+VALUES ($1, $2, nextval('kv_store_version_seq'))
+        "#,
+        namespace,
+        key
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Records the deletion of every live pair of `namespace`; must run before the pairs are deleted.
+pub async fn record_flush(executor: impl PgExecutor<'_>, namespace: &str) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+INSERT INTO kv_history (namespace, key, version)
+SELECT namespace, key, nextval('kv_store_version_seq')
+FROM kv_store
+WHERE namespace = $1
+  AND (expires_at IS NULL OR expires_at > NOW())
+        "#,
+        namespace
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Lists the recorded versions of a pair, newest first.
+pub async fn list(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+) -> sqlx::Result<Vec<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+SELECT version, value, content_type, expires_at, recorded_at
+FROM kv_history
+WHERE namespace = $1
+  AND key = $2
+ORDER BY version DESC
+        "#,
+        namespace,
+        key
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Fetches a single recorded version of a pair.
+pub async fn find(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    version: i64,
+) -> sqlx::Result<Option<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+SELECT version, value, content_type, expires_at, recorded_at
+FROM kv_history
+WHERE namespace = $1
+  AND key = $2
+  AND version = $3
+        "#,
+        namespace,
+        key,
+        version
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Fetches the version of a pair that was current at `at`, if any. The version may be a delete or
+/// may have expired by then.
+pub async fn as_of(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    at: DateTime<Utc>,
+) -> sqlx::Result<Option<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+SELECT version, value, content_type, expires_at, recorded_at
+FROM kv_history
+WHERE namespace = $1
+  AND key = $2
+  AND recorded_at <= $3
+ORDER BY version DESC
+LIMIT 1
+        "#,
+        namespace,
+        key,
+        at
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Drops versions outside of `retention` and returns how many were removed.
+pub async fn prune(executor: impl PgExecutor<'_>, retention: &Retention) -> sqlx::Result<u64> {
+    let max_age = retention.max_age.map(|age| age.as_secs() as i64);
+    let result = sqlx::query!(
+        r#"
+DELETE FROM kv_history h
+USING (SELECT namespace,
+              key,
+              version,
+              value IS NULL AS deleted,
+              recorded_at,
+              row_number() OVER (PARTITION BY namespace, key ORDER BY version DESC) AS rank,
+              lead(recorded_at) OVER (PARTITION BY namespace, key ORDER BY version) AS superseded_at
+       FROM kv_history) v
+WHERE h.namespace = v.namespace
+  AND h.key = v.key
+  AND h.version = v.version
+  AND (v.rank > $1
+      OR v.superseded_at <= NOW() - $2::BIGINT * INTERVAL '1 second'
+      -- a delete that is still current only hides older versions, which are gone by now
+      OR (v.deleted AND v.rank = 1 AND v.recorded_at <= NOW() - $2::BIGINT * INTERVAL '1 second'))
+        "#,
+        retention.max_versions,
+        max_age
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prune, record_delete, record_write, Retention};
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    async fn versions(pool: &PgPool) -> sqlx::Result<Vec<i64>> {
+        sqlx::query_scalar!("SELECT version FROM kv_history ORDER BY version")
+            .fetch_all(pool)
+            .await
+    }
+
+    #[sqlx::test]
+    async fn prunes_by_count_and_age(pool: PgPool) -> sqlx::Result<()> {
+        let mut written = Vec::new();
+        for _ in 0..3 {
+            let version =
+                sqlx::query_scalar!(r#"SELECT nextval('kv_store_version_seq') AS "version!""#)
+                    .fetch_one(&pool)
+                    .await?;
+            record_write(&pool, "ns", "key_1", version, b"value", "text/plain", None).await?;
+            written.push(version);
+        }
+
+        let retention = Retention {
+            max_versions: Some(2),
+            max_age: None,
+        };
+        assert_eq!(prune(&pool, &retention).await?, 1);
+        assert_eq!(versions(&pool).await?, written[1..]);
+
+        // only versions superseded before the cutoff are dropped, the current one is kept
+        sqlx::query!("UPDATE kv_history SET recorded_at = NOW() - INTERVAL '1 hour'")
+            .execute(&pool)
+            .await?;
+        let retention = Retention {
+            max_versions: None,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(prune(&pool, &retention).await?, 1);
+        assert_eq!(versions(&pool).await?, written[2..]);
+
+        // a delete past the cutoff leaves nothing to keep
+        record_delete(&pool, "ns", "key_1").await?;
+        sqlx::query!("UPDATE kv_history SET recorded_at = NOW() - INTERVAL '1 hour'")
+            .execute(&pool)
+            .await?;
+        assert_eq!(prune(&pool, &retention).await?, 2);
+        assert!(versions(&pool).await?.is_empty());
+
+        Ok(())
+    }
+}
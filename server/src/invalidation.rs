@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+
+/// Channel on which every instance announces the pairs it has changed.
+pub const CHANNEL: &str = "kv_invalidate";
+
+/// Tells other instances to drop a pair, or a whole namespace when `key` is `None`, from their
+/// caches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invalidation {
+    /// Instance that made the change, which has already updated its own cache.
+    pub origin: String,
+    pub namespace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl Invalidation {
+    pub fn key(origin: &str, namespace: &str, key: &str) -> Self {
+        Self {
+            origin: origin.to_string(),
+            namespace: namespace.to_string(),
+            key: Some(key.to_string()),
+        }
+    }
+
+    pub fn namespace(origin: &str, namespace: &str) -> Self {
+        Self {
+            origin: origin.to_string(),
+            namespace: namespace.to_string(),
+            key: None,
+        }
+    }
+}
+
+/// Publishes `invalidations` in a single round trip. Inside a transaction, they are only delivered
+/// once it commits, and not at all if it rolls back.
+pub async fn publish(
+    executor: impl PgExecutor<'_>,
+    invalidations: &[Invalidation],
+) -> sqlx::Result<()> {
+    let payloads = invalidations
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| sqlx::Error::Encode(err.into()))?;
+
+    sqlx::query!(
+        "SELECT pg_notify($1, payload) FROM UNNEST($2::TEXT[]) AS payload",
+        CHANNEL,
+        &payloads
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
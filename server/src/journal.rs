@@ -0,0 +1,68 @@
+use crate::{changes, history};
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+
+/// A value as committed by a write, to be recorded alongside it.
+#[derive(Debug, Clone)]
+pub struct Put<'a> {
+    pub namespace: &'a str,
+    pub key: &'a str,
+    pub version: i64,
+    pub value: &'a [u8],
+    pub content_type: &'a str,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Records a write in the change log, and in history if `history` is enabled. Runs in the
+/// transaction of the write.
+pub async fn record_put(conn: &mut PgConnection, history: bool, put: &Put<'_>) -> sqlx::Result<()> {
+    if history {
+        history::record_write(
+            &mut *conn,
+            put.namespace,
+            put.key,
+            put.version,
+            put.value,
+            put.content_type,
+            put.expires_at,
+        )
+        .await?;
+    }
+
+    changes::record_put(
+        &mut *conn,
+        put.namespace,
+        put.key,
+        put.version,
+        put.value,
+        put.content_type,
+    )
+    .await
+}
+
+/// Records the deletion of a live pair, like [`record_put`].
+pub async fn record_delete(
+    conn: &mut PgConnection,
+    history: bool,
+    namespace: &str,
+    key: &str,
+) -> sqlx::Result<()> {
+    if history {
+        history::record_delete(&mut *conn, namespace, key).await?;
+    }
+
+    changes::record_delete(&mut *conn, namespace, key).await
+}
+
+/// Records the deletion of every live pair of `namespace`; must run before the pairs are deleted.
+pub async fn record_flush(
+    conn: &mut PgConnection,
+    history: bool,
+    namespace: &str,
+) -> sqlx::Result<()> {
+    if history {
+        history::record_flush(&mut *conn, namespace).await?;
+    }
+
+    changes::record_flush(&mut *conn, namespace).await
+}
@@ -1,9 +1,26 @@
 #![forbid(unsafe_code)]
+// the original route tests pass `&json!(...)` to `set_json`
+#![cfg_attr(test, allow(clippy::needless_borrows_for_generic_args))]
 
+pub mod auth;
 pub mod cache;
+pub mod changes;
+pub mod config;
 pub mod error;
+pub mod health;
+pub mod history;
+pub mod invalidation;
+pub mod journal;
+pub mod metrics;
+pub mod namespace;
+pub mod precondition;
+pub mod rate_limit;
 pub mod routes;
 pub mod state;
+pub mod store;
+pub mod tasks;
+pub mod value;
+pub mod write_back;
 
 #[cfg(test)]
 pub mod test_utils;
@@ -1,14 +1,34 @@
+use actix_web::middleware::from_fn;
 use actix_web::{http::header::ContentType, web, App, HttpResponse, HttpServer, Responder};
 use dotenvy::dotenv;
-use num_cpus;
+use server::auth::{self, Auth, Grant};
+use server::cache::{Cache, Capacity, WritePolicies};
+use server::config::{self, Config};
+use server::health::{self, Health};
+use server::history::Retention;
+use server::metrics::{self, Metrics};
+use server::rate_limit::{self, Budget, RateLimiter};
 use server::routes;
 use server::state::AppState;
+use server::tasks::{compactor, flusher, listener, pruner, reaper};
+use server::write_back::{self, Scope, WriteBack};
 use sqlx::postgres::PgPoolOptions;
 use std::env;
-use tracing_subscriber;
+use std::time::{Duration, Instant};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
-const DEFAULT_POOL_SIZE: u32 = 32;
-const DEFAULT_CACHE_SIZE: u64 = 128_000;
+/// Resolves once the process is asked to shut down.
+async fn shutdown_signal() {
+    let mut terminate =
+        actix_rt::signal::unix::signal(actix_rt::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = actix_rt::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
 
 async fn not_found() -> impl Responder {
     HttpResponse::NotFound()
@@ -18,26 +38,62 @@ async fn not_found() -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    let metrics = Metrics::new();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(metrics.query_layer())
+        .init();
     dotenv().ok();
 
-    let bind = env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:6464".into());
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
-    let db_pool_size: u32 = env::var("DB_POOL_SIZE")
-        .ok()
-        .and_then(|x| x.parse().ok())
-        .unwrap_or(DEFAULT_POOL_SIZE);
-    let cache_size: u64 = env::var("CACHE_SIZE")
-        .ok()
-        .and_then(|x| x.parse().ok())
-        .unwrap_or(DEFAULT_CACHE_SIZE);
+    let args = config::command().get_matches();
+    let config = match Config::load(&args, |name| env::var(name).ok()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("configuration error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if args.get_flag("print-config") {
+        print!("{}", config.to_redacted_toml());
+        return Ok(());
+    }
+
+    // a memory budget takes precedence over the number of entries
+    let cache_capacity = match config.cache.max_bytes {
+        Some(bytes) => Capacity::Bytes(bytes),
+        None => Capacity::Entries(config.cache.size),
+    };
+    let write_policies = WritePolicies {
+        default: config.cache.write_policy,
+        namespaces: config
+            .cache
+            .namespace_write_policies
+            .clone()
+            .into_iter()
+            .collect(),
+    };
+    let retention = Retention {
+        max_versions: config.history.max_versions,
+        max_age: config.history.max_age_secs.map(Duration::from_secs),
+    };
+    // unlimited unless a rate is set
+    let rate_limit_read = config
+        .rate_limit
+        .read_per_sec
+        .map(|per_sec| Budget::new(per_sec, config.rate_limit.read_burst));
+    let rate_limit_write = config
+        .rate_limit
+        .write_per_sec
+        .map(|per_sec| Budget::new(per_sec, config.rate_limit.write_burst));
 
     let pool = PgPoolOptions::new()
-        .max_connections(db_pool_size)
-        .connect(&database_url)
+        .max_connections(config.database.pool_size)
+        // reported to the metrics
+        .acquire_time_level(log::LevelFilter::Trace)
+        .connect(&config.database.url)
         .await?;
 
-    match sqlx::migrate!("./migrations").run(&pool).await {
+    match health::MIGRATOR.run(&pool).await {
         Ok(_) => println!("ran database migrations"),
         Err(err) => {
             eprintln!("failed to run database migrations: {:?}", err);
@@ -45,21 +101,165 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    let state = AppState::new(pool, cache_size).await;
-    let data = web::Data::new(state);
+    // bootstraps key management, as managing keys takes a key
+    if let Some(token) = &config.auth.admin_key {
+        let grants = [Grant {
+            namespace: auth::ALL_NAMESPACES.to_string(),
+            scope: auth::Scope::Admin,
+        }];
+        auth::replace(&pool, "admin", token, &grants).await?;
+    }
+    if config.auth.enabled && auth::list(&pool).await?.is_empty() {
+        eprintln!("authentication is enabled but there are no API keys, set AUTH_ADMIN_KEY");
+    }
 
-    println!("starting at http://{}", bind);
+    // stopped on shutdown, before the pool is closed
+    let mut tasks = Vec::new();
 
-    HttpServer::new(move || {
+    // deletes expired pairs from the database; the cache expires them on its own
+    tasks.push(actix_rt::spawn(reaper::run(
+        pool.clone(),
+        Duration::from_secs(config.reaper.interval_secs),
+        config.reaper.batch_size,
+    )));
+
+    // history is kept even when disabled later on, so it is pruned regardless
+    if retention.max_versions.is_some() || retention.max_age.is_some() {
+        tasks.push(actix_rt::spawn(pruner::run(
+            pool.clone(),
+            Duration::from_secs(config.history.prune_interval_secs),
+            retention,
+        )));
+    }
+
+    // trims the change log up to the lowest consumer checkpoint, or by age without consumers
+    tasks.push(actix_rt::spawn(compactor::run(
+        pool.clone(),
+        Duration::from_secs(config.changes.compaction_interval_secs),
+        config.changes.compaction_batch_size,
+        Duration::from_secs(config.changes.retention_secs),
+    )));
+
+    let mut cache = Cache::with_capacity(cache_capacity, config.cache.eviction)
+        .with_max_entry_bytes(config.cache.max_entry_bytes)
+        .with_write_policies(write_policies);
+    if config.cache.negative_size > 0 {
+        cache = cache.with_negative_cache(
+            config.cache.negative_size,
+            Duration::from_millis(config.cache.negative_ttl_ms),
+        );
+    }
+    let mut state = AppState::new(pool.clone(), cache)
+        .await
+        .with_history(config.history.enabled)
+        .with_metrics(metrics)
+        .with_health(Health::new(Duration::from_millis(
+            config.health.check_timeout_ms,
+        )));
+    if config.auth.enabled {
+        state = state.with_auth(Auth::new(Duration::from_millis(config.auth.cache_ttl_ms)));
+    }
+    if rate_limit_read.is_some() || rate_limit_write.is_some() {
+        state = state.with_rate_limit(RateLimiter::new(rate_limit_read, rate_limit_write));
+    }
+    if config.write_back.enabled {
+        state = state.with_write_back(
+            WriteBack::new(config.write_back.max_batch).with_max_dirty(config.write_back.max_dirty),
+        );
+    }
+
+    // writes buffered writes back to the database
+    let flusher = config.write_back.enabled.then(|| {
+        actix_rt::spawn(flusher::run(
+            state.clone(),
+            Duration::from_millis(config.write_back.interval_ms),
+        ))
+    });
+
+    // drops pairs changed by other instances from the cache and feeds watchers
+    let listener = actix_rt::spawn(listener::run(state.clone()));
+
+    let data = web::Data::new(state.clone());
+
+    println!("starting at http://{}", config.bind_address);
+
+    // signals are handled below, so that readiness fails before the server stops
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .wrap(from_fn(rate_limit::limit))
+            .wrap(from_fn(auth::authenticate))
+            .wrap(from_fn(metrics::track))
             .configure(routes::init_routes)
             .default_service(web::route().to(not_found))
     })
     .workers(num_cpus::get()) // one worker per cpu core
-    .bind(bind)?
-    .run()
-    .await?;
+    .disable_signals()
+    .shutdown_timeout(config.shutdown.drain_timeout_secs)
+    .bind(&config.bind_address)?
+    .run();
+    let handle = server.handle();
+    let mut server = actix_rt::spawn(server);
+
+    let stopped = tokio::select! {
+        _ = shutdown_signal() => None,
+        stopped = &mut server => Some(stopped),
+    };
+
+    let shutdown_started = Instant::now();
+    state.health.start_draining();
+    let stopped = match stopped {
+        Some(stopped) => stopped,
+        None => {
+            // load balancers stop routing here once they see readiness fail
+            actix_rt::time::sleep(Duration::from_millis(config.shutdown.delay_ms)).await;
+
+            let in_flight = state.metrics.in_flight();
+            let handled = state.metrics.handled();
+            let aborted = state.metrics.aborted();
+            println!(
+                "shutting down with {} requests in flight, draining connections for up to {}s",
+                in_flight, config.shutdown.drain_timeout_secs
+            );
+
+            // stops accepting connections, then waits for the workers up to the drain timeout
+            handle.stop(true).await;
+            let stopped = server.await;
+            println!(
+                "handled {} requests while draining, {} aborted",
+                state.metrics.handled() - handled,
+                state.metrics.aborted() - aborted
+            );
+            stopped
+        }
+    };
+
+    // nothing is written anymore, so whatever is still buffered is written back for good
+    let mut written_back = 0;
+    if let Some(flusher) = flusher {
+        flusher.abort();
+        let _ = flusher.await;
+        match write_back::flush(&state, Scope::All).await {
+            Ok(count) => written_back = count,
+            Err(err) => eprintln!("failed to write back dirty pairs: {:?}", err),
+        }
+    }
+
+    // the listener returns its connection on drop, which needs the runtime to still be running
+    listener.abort();
+    let _ = listener.await;
+    for task in tasks {
+        task.abort();
+        let _ = task.await;
+    }
+
+    // waits for every connection to be returned
+    pool.close().await;
+    println!(
+        "shut down in {:?}, wrote back {} pairs",
+        shutdown_started.elapsed(),
+        written_back
+    );
 
-    Ok(())
+    Ok(stopped??)
 }
@@ -0,0 +1,447 @@
+use crate::state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::web;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Route label of requests that matched no route, so that arbitrary paths do not each get their
+/// own series.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Target of the events sqlx logs for every statement it runs.
+const QUERY_TARGET: &str = "sqlx::query";
+/// Target of the events sqlx logs for every connection taken from the pool.
+const ACQUIRE_TARGET: &str = "sqlx::pool::acquire";
+
+/// Request and database metrics, exposed by `GET /metrics` along with the state of the cache and
+/// the pool.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    in_flight: IntGauge,
+    aborted: IntCounter,
+    query_duration: HistogramVec,
+    acquire_duration: Histogram,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "Requests handled, by route and status",
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle requests, by route",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+        let in_flight = IntGauge::new("http_requests_in_flight", "Requests being handled")
+            .expect("valid metric");
+        let aborted = IntCounter::new(
+            "http_requests_aborted_total",
+            "Requests dropped before a response, by the client or at shutdown",
+        )
+        .expect("valid metric");
+        let query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time taken by database statements, by their first keyword",
+            ),
+            &["statement"],
+        )
+        .expect("valid metric");
+        let acquire_duration = Histogram::with_opts(HistogramOpts::new(
+            "db_pool_acquire_duration_seconds",
+            "Time waited for a connection from the pool",
+        ))
+        .expect("valid metric");
+
+        let registry = Registry::new();
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn Collector>,
+            Box::new(request_duration.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(aborted.clone()),
+            Box::new(query_duration.clone()),
+            Box::new(acquire_duration.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            in_flight,
+            aborted,
+            query_duration,
+            acquire_duration,
+        }
+    }
+
+    /// Requests being handled right now.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.get().try_into().unwrap_or(0)
+    }
+
+    /// Requests handled so far, whatever their outcome.
+    pub fn handled(&self) -> u64 {
+        self.requests
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum()
+    }
+
+    /// Requests dropped before a response so far.
+    pub fn aborted(&self) -> u64 {
+        self.aborted.get()
+    }
+
+    fn observe_request(&self, method: &str, route: &str, status: StatusCode, elapsed: Duration) {
+        self.requests
+            .with_label_values(&[method, route, status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// A tracing layer recording the latency sqlx reports for statements and pool acquisitions.
+    /// The pool only reports acquisitions if its `acquire_time_level` is enabled.
+    pub fn query_layer<S>(&self) -> impl Layer<S> + use<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        QueryLayer {
+            query_duration: self.query_duration.clone(),
+            acquire_duration: self.acquire_duration.clone(),
+        }
+        .with_filter(
+            Targets::new()
+                .with_target(QUERY_TARGET, Level::TRACE)
+                .with_target(ACQUIRE_TARGET, Level::TRACE),
+        )
+    }
+}
+
+/// Counts a request as in flight until dropped, and as aborted if dropped before it finished.
+struct InFlight<'a> {
+    metrics: &'a Metrics,
+    finished: bool,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(metrics: &'a Metrics) -> Self {
+        metrics.in_flight.inc();
+        Self {
+            metrics,
+            finished: false,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.metrics.in_flight.dec();
+        if !self.finished {
+            self.metrics.aborted.inc();
+        }
+    }
+}
+
+/// Counts the requests to every route and how long they take.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let Some(metrics) = req
+        .app_data::<web::Data<AppState>>()
+        .map(|data| data.metrics.clone())
+    else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let mut in_flight = InFlight::start(&metrics);
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let started = Instant::now();
+
+    let res = next.call(req).await;
+    in_flight.finished = true;
+
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    metrics.observe_request(&method, &route, status, started.elapsed());
+
+    res.map(ServiceResponse::map_into_boxed_body)
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render(state: &AppState) -> String {
+    let mut families = state.metrics.registry.gather();
+    families.extend(state_families(state));
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .expect("metrics are encodable");
+    String::from_utf8(buffer).expect("metrics are UTF-8")
+}
+
+/// Metrics read from the cache and the pool when scraped, rather than tracked as they change.
+fn state_families(state: &AppState) -> Vec<MetricFamily> {
+    let cache = state.cache.stats();
+    let write_back = state.write_back.stats();
+    let pool = &state.db_pool;
+
+    [
+        counter(
+            "cache_hits_total",
+            "Cache reads that found the entry",
+            cache.hits,
+        ),
+        counter(
+            "cache_misses_total",
+            "Cache reads that missed",
+            cache.misses,
+        ),
+        counter(
+            "cache_coalesced_total",
+            "Misses that waited for a load already in progress",
+            cache.coalesced,
+        ),
+        counter(
+            "cache_negative_hits_total",
+            "Misses answered by the negative cache",
+            cache.negative_hits,
+        ),
+        counter(
+            "cache_insertions_total",
+            "Entries inserted into the cache",
+            cache.insertions,
+        ),
+        counter(
+            "cache_evictions_total",
+            "Entries evicted to make room for others",
+            cache.evictions,
+        ),
+        gauge("cache_entries", "Entries in the cache", cache.entries),
+        gauge(
+            "cache_weighted_size",
+            "Size of the cached entries, in the unit of the capacity",
+            cache.weighted_size,
+        ),
+        gauge("cache_capacity", "Capacity of the cache", cache.capacity),
+        gauge(
+            "write_back_dirty",
+            "Pairs waiting to be written to the database",
+            write_back.dirty,
+        ),
+        counter(
+            "write_back_dropped_total",
+            "Buffered writes dropped as the database rejected them",
+            write_back.dropped,
+        ),
+        counter(
+            "rate_limited_requests_total",
+            "Requests rejected for exceeding a client's budget",
+            state.rate_limit.limited(),
+        ),
+        gauge(
+            "rate_limit_clients",
+            "Clients whose requests are tracked by the rate limiter",
+            state.rate_limit.clients(),
+        ),
+        gauge(
+            "db_pool_connections",
+            "Connections held by the pool",
+            pool.size().into(),
+        ),
+        gauge(
+            "db_pool_idle_connections",
+            "Connections idle in the pool",
+            pool.num_idle() as u64,
+        ),
+        gauge(
+            "db_pool_max_connections",
+            "Connections the pool may hold",
+            pool.options().get_max_connections().into(),
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn counter(name: &str, help: &str, value: u64) -> Vec<MetricFamily> {
+    let counter = IntCounter::new(name, help).expect("valid metric");
+    counter.inc_by(value);
+    counter.collect()
+}
+
+fn gauge(name: &str, help: &str, value: u64) -> Vec<MetricFamily> {
+    let gauge = IntGauge::new(name, help).expect("valid metric");
+    gauge.set(value.try_into().unwrap_or(i64::MAX));
+    gauge.collect()
+}
+
+struct QueryLayer {
+    query_duration: HistogramVec,
+    acquire_duration: Histogram,
+}
+
+impl<S: Subscriber> Layer<S> for QueryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = SqlxFields::default();
+        event.record(&mut fields);
+
+        match event.metadata().target() {
+            QUERY_TARGET => {
+                if let Some(elapsed) = fields.elapsed_secs {
+                    let statement = fields
+                        .summary
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or("")
+                        .to_lowercase();
+                    self.query_duration
+                        .with_label_values(&[statement.as_str()])
+                        .observe(elapsed);
+                }
+            }
+            ACQUIRE_TARGET => {
+                if let Some(elapsed) = fields.aquired_after_secs {
+                    self.acquire_duration.observe(elapsed);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fields of the sqlx events of interest.
+#[derive(Default)]
+struct SqlxFields {
+    summary: String,
+    elapsed_secs: Option<f64>,
+    // sic, as spelled by sqlx
+    aquired_after_secs: Option<f64>,
+}
+
+impl Visit for SqlxFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        match field.name() {
+            "elapsed_secs" => self.elapsed_secs = Some(value),
+            "aquired_after_secs" => self.aquired_after_secs = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "summary" {
+            self.summary = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "summary" {
+            self.summary = format!("{:?}", value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::cache::Cache;
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::setup_test_app_with_state;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[sqlx::test]
+    async fn exposes_request_cache_and_query_metrics(pool: PgPool) -> sqlx::Result<()> {
+        let metrics = Metrics::new();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(metrics.query_layer()),
+        );
+        let state = AppState::new(pool, Cache::new(64))
+            .await
+            .with_metrics(metrics);
+        let app = setup_test_app_with_state(state).await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_1"}))
+            .to_request();
+        test::call_service(&app, req).await;
+        for uri in ["/key_1", "/key_1", "/key_2"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = str::from_utf8(&body).unwrap();
+
+        assert!(body.contains(r#"http_requests_total{method="GET",route="/{key}",status="200"} 2"#));
+        assert!(body.contains(r#"http_requests_total{method="GET",route="/{key}",status="404"} 1"#));
+        assert!(body.contains(r#"http_request_duration_seconds_count{method="POST",route="/"} 1"#));
+        assert!(body.contains("http_requests_in_flight 1"));
+        assert!(body.contains("http_requests_aborted_total 0"));
+        assert!(body.contains("cache_hits_total 2"));
+        assert!(body.contains("cache_misses_total 1"));
+        assert!(body.contains("cache_insertions_total 1"));
+        assert!(body.contains(r#"db_query_duration_seconds_count{statement="select"}"#));
+        assert!(body.contains("db_pool_max_connections"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use crate::cache::CacheKey;
+use serde::Deserialize;
+use std::borrow::Cow;
+use validator::{Validate, ValidationError};
+
+/// Namespace of pairs accessed through routes that do not name one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+pub fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// Namespaces are 1 to 64 ASCII letters, digits, `-` or `_`.
+pub fn validate_namespace(namespace: &str) -> Result<(), ValidationError> {
+    let valid = (1..=64).contains(&namespace.len())
+        && namespace
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("namespace").with_message(Cow::Borrowed("invalid namespace")))
+    }
+}
+
+/// Keys are 1 to 512 bytes without control characters, which PostgreSQL may refuse to store.
+pub fn validate_key(key: &str) -> Result<(), ValidationError> {
+    if !(1..=512).contains(&key.len()) {
+        return Err(ValidationError::new("key").with_message(Cow::Borrowed("invalid key length")));
+    }
+    if key.chars().any(char::is_control) {
+        return Err(ValidationError::new("key").with_message(Cow::Borrowed(
+            "invalid key: control characters are not allowed",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Path of routes addressing a whole namespace, mounted both at the root and under
+/// `/ns/{namespace}`.
+#[derive(Debug, Deserialize, Validate)]
+pub struct NamespacePath {
+    #[serde(default = "default_namespace")]
+    #[validate(custom(function = "validate_namespace"))]
+    pub namespace: String,
+}
+
+/// Path of routes addressing a single pair, `/{key}` or `/ns/{namespace}/{key}`.
+#[derive(Debug, Deserialize, Validate)]
+pub struct KeyPath {
+    #[serde(default = "default_namespace")]
+    #[validate(custom(function = "validate_namespace"))]
+    pub namespace: String,
+    #[validate(custom(function = "validate_key"))]
+    pub key: String,
+}
+
+impl KeyPath {
+    pub fn cache_key(&self) -> CacheKey {
+        CacheKey::new(self.namespace.clone(), self.key.clone())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::AppError;
+use actix_web::http::header::{self, EntityTag, Header, IfMatch, IfNoneMatch};
+use actix_web::HttpRequest;
+
+/// Condition a write must satisfy against the current version of a pair.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Precondition {
+    /// Blind write.
+    None,
+    /// `If-Match: *`, the pair must exist.
+    Exists,
+    /// `If-None-Match: *`, the pair must not exist.
+    Absent,
+    /// `If-Match: "<version>", ...`, the pair must currently be at one of these versions.
+    Matches(Vec<i64>),
+}
+
+impl Precondition {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, AppError> {
+        let has_if_match = req.headers().contains_key(header::IF_MATCH);
+        let has_if_none_match = req.headers().contains_key(header::IF_NONE_MATCH);
+
+        match (has_if_match, has_if_none_match) {
+            (false, false) => Ok(Precondition::None),
+            (true, true) => Err(AppError::BadRequest(
+                "If-Match and If-None-Match cannot be combined".into(),
+            )),
+            (true, false) => match IfMatch::parse(req) {
+                Ok(IfMatch::Any) => Ok(Precondition::Exists),
+                // weak tags never match, and neither does anything that is not a version
+                Ok(IfMatch::Items(tags)) => Ok(Precondition::Matches(
+                    tags.iter()
+                        .filter(|tag| !tag.weak)
+                        .filter_map(|tag| tag.tag().parse().ok())
+                        .collect(),
+                )),
+                Err(_) => Err(AppError::BadRequest("invalid If-Match header".into())),
+            },
+            (false, true) => match IfNoneMatch::parse(req) {
+                Ok(IfNoneMatch::Any) => Ok(Precondition::Absent),
+                _ => Err(AppError::BadRequest(
+                    "only `If-None-Match: *` is supported on writes".into(),
+                )),
+            },
+        }
+    }
+}
+
+/// Entity tag sent for a pair at `version`.
+pub fn etag(version: i64) -> EntityTag {
+    EntityTag::new_strong(version.to_string())
+}
@@ -0,0 +1,417 @@
+use crate::auth::{self, ApiKey, Scope};
+use crate::error::AppError;
+use crate::state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clients tracked at most, the least recently seen being forgotten first.
+const MAX_CLIENTS: u64 = 100_000;
+
+/// Clients idle for this long are forgotten, along with their usage, and start over with full
+/// budgets.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Requests a client may make: `burst` at once, refilled at `per_sec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Budget {
+    /// A budget of `per_sec` requests per second, allowing bursts of `burst` requests, or of a
+    /// second's worth if `None`.
+    pub fn new(per_sec: f64, burst: Option<f64>) -> Self {
+        Self {
+            per_sec,
+            burst: burst.unwrap_or(per_sec).max(1.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn full(budget: Option<Budget>, now: Instant) -> Self {
+        Self {
+            tokens: budget.map_or(0.0, |budget| budget.burst),
+            refilled_at: now,
+        }
+    }
+
+    /// Tokens available at `now`.
+    fn refilled(&self, budget: Budget, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        (self.tokens + elapsed.as_secs_f64() * budget.per_sec).min(budget.burst)
+    }
+
+    /// Returns how long until a token is available, if none is.
+    fn wait(&self, budget: Budget, now: Instant) -> Result<(), Duration> {
+        let tokens = self.refilled(budget, now);
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) / budget.per_sec));
+        }
+        Ok(())
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, budget: Budget, now: Instant) -> Result<(), Duration> {
+        self.wait(budget, now)?;
+        self.tokens = self.refilled(budget, now) - 1.0;
+        self.refilled_at = now;
+        Ok(())
+    }
+}
+
+/// Requests made by a client since it was first seen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub reads: u64,
+    pub writes: u64,
+    /// requests rejected for exceeding a budget
+    pub limited: u64,
+}
+
+#[derive(Debug)]
+struct Client {
+    read: Bucket,
+    write: Bucket,
+    usage: Usage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    pub rate_limit: bool,
+    /// usage by client, `key:<name>` for authenticated requests and `ip:<address>` otherwise
+    pub clients: BTreeMap<String, Usage>,
+}
+
+#[derive(Debug, Clone)]
+struct Limits {
+    read: Option<Budget>,
+    write: Option<Budget>,
+    clients: moka::sync::Cache<String, Arc<Mutex<Client>>>,
+}
+
+/// Limits the requests of each client with token buckets, one for reads and one for writes.
+/// Disabled unless built with [`RateLimiter::new`].
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    limits: Option<Limits>,
+    /// Requests rejected by every client, including forgotten ones.
+    limited: Arc<AtomicU64>,
+}
+
+impl RateLimiter {
+    /// Enables rate limiting, reads and writes being unlimited if their budget is `None`.
+    pub fn new(read: Option<Budget>, write: Option<Budget>) -> Self {
+        Self {
+            limits: Some(Limits {
+                read,
+                write,
+                clients: moka::sync::Cache::builder()
+                    .max_capacity(MAX_CLIENTS)
+                    .time_to_idle(CLIENT_IDLE_TIMEOUT)
+                    .build(),
+            }),
+            limited: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limits.is_some()
+    }
+
+    /// Counts a request of `client` needing `scope`, rejecting it if over budget. Reads take from
+    /// the read budget, anything else from the write budget.
+    pub fn check(&self, client: &str, scope: Scope) -> Result<(), AppError> {
+        let Some(limits) = &self.limits else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let client = limits.clients.get_with_by_ref(client, || {
+            Arc::new(Mutex::new(Client {
+                read: Bucket::full(limits.read, now),
+                write: Bucket::full(limits.write, now),
+                usage: Usage::default(),
+            }))
+        });
+        let mut client = client.lock().unwrap();
+        let client = &mut *client;
+
+        let (bucket, budget) = if scope == Scope::Read {
+            client.usage.reads += 1;
+            (&mut client.read, limits.read)
+        } else {
+            client.usage.writes += 1;
+            (&mut client.write, limits.write)
+        };
+        let Some(budget) = budget else {
+            return Ok(());
+        };
+
+        bucket.take(budget, now).map_err(|retry_after| {
+            client.usage.limited += 1;
+            self.rejected(retry_after)
+        })
+    }
+
+    /// Rejects a request of `client` needing `scope` if over budget, like [`RateLimiter::check`],
+    /// but without counting it against the budget.
+    pub fn peek(&self, client: &str, scope: Scope) -> Result<(), AppError> {
+        let Some(limits) = &self.limits else {
+            return Ok(());
+        };
+        let Some(client) = limits.clients.get(client) else {
+            return Ok(());
+        };
+        let mut client = client.lock().unwrap();
+        let client = &mut *client;
+
+        let (bucket, budget, count) = if scope == Scope::Read {
+            (&client.read, limits.read, &mut client.usage.reads)
+        } else {
+            (&client.write, limits.write, &mut client.usage.writes)
+        };
+        let Some(budget) = budget else {
+            return Ok(());
+        };
+
+        bucket.wait(budget, Instant::now()).map_err(|retry_after| {
+            // rejected requests are counted all the same
+            *count += 1;
+            client.usage.limited += 1;
+            self.rejected(retry_after)
+        })
+    }
+
+    fn rejected(&self, retry_after: Duration) -> AppError {
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        // whole seconds, rounded up so that retrying right away is not rejected again
+        AppError::TooManyRequests(retry_after.as_secs_f64().ceil().max(1.0) as u64)
+    }
+
+    /// Requests rejected so far.
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+
+    /// Clients tracked, approximately.
+    pub fn clients(&self) -> u64 {
+        self.limits
+            .as_ref()
+            .map_or(0, |limits| limits.clients.entry_count())
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            rate_limit: self.is_enabled(),
+            clients: self
+                .limits
+                .iter()
+                .flat_map(|limits| limits.clients.iter())
+                .map(|(client, usage)| (client.to_string(), usage.lock().unwrap().usage))
+                .collect(),
+        }
+    }
+}
+
+/// Identifies the client of a request by its address. Forwarding headers are ignored, as clients
+/// could set them to anything.
+fn address(req: &ServiceRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+/// Identifies the client of a request by its API key if authenticated, by its address otherwise.
+fn client(req: &ServiceRequest) -> String {
+    match req.extensions().get::<ApiKey>() {
+        Some(key) => format!("key:{}", key.name),
+        None => address(req),
+    }
+}
+
+/// The budget a request takes from: admin routes only reading, such as `GET /stats`, count as
+/// reads.
+fn budgeted(req: &ServiceRequest, scope: Scope) -> Scope {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        Scope::Read
+    } else {
+        scope
+    }
+}
+
+/// Rejects a request needing `scope` before it is authenticated if its address is over budget,
+/// without counting it. Only requests failing authentication take from the budget of an address
+/// then, see [`count_failure`], so that clients trying out tokens are turned away before each one
+/// is looked up.
+pub(crate) fn admit(
+    rate_limit: &RateLimiter,
+    req: &ServiceRequest,
+    scope: Scope,
+) -> Result<(), AppError> {
+    rate_limit.peek(&address(req), budgeted(req, scope))
+}
+
+/// Counts a request that failed authentication against the budget of its address.
+pub(crate) fn count_failure(
+    rate_limit: &RateLimiter,
+    req: &ServiceRequest,
+    scope: Scope,
+) -> Result<(), AppError> {
+    rate_limit.check(&address(req), budgeted(req, scope))
+}
+
+/// Rejects requests of clients over budget, if rate limiting is enabled. Runs after
+/// authentication, so that authenticated clients are limited by key.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    if let Some(state) = state.filter(|state| state.rate_limit.is_enabled())
+        && let Some((_, scope)) = auth::required(&req)
+        && let Err(err) = state.rate_limit.check(&client(&req), budgeted(&req, scope))
+    {
+        return Ok(req.error_response(err));
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, RateLimitStats, RateLimiter};
+    use crate::auth::{self, Auth, Grant, Scope};
+    use crate::cache::Cache;
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::setup_test_app_with_state;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn limits_reads_and_writes_separately(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool, Cache::new(64))
+            .await
+            .with_rate_limit(RateLimiter::new(
+                Some(Budget::new(0.1, Some(2.0))),
+                Some(Budget::new(0.1, Some(1.0))),
+            ));
+        let app = setup_test_app_with_state(state).await;
+        let client = "10.0.0.1:4000".parse().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .peer_addr(client)
+            .set_json(json!({"key": "key_1", "value": "value_1"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        for status in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/key_1")
+                .peer_addr(client)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "10");
+            }
+        }
+
+        // other clients have budgets of their own
+        let req = test::TestRequest::get()
+            .uri("/key_1")
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let stats: RateLimitStats = test::call_and_read_body_json(&app, req).await;
+        assert!(stats.rate_limit);
+        let usage = stats.clients["ip:10.0.0.1"];
+        assert_eq!((usage.reads, usage.writes, usage.limited), (3, 1, 1));
+        assert_eq!(stats.clients["ip:10.0.0.2"].reads, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn limits_failed_authentication_by_address(pool: PgPool) -> sqlx::Result<()> {
+        let admin = auth::generate_token();
+        auth::replace(
+            &pool,
+            "admin",
+            &admin,
+            &[Grant {
+                namespace: "*".into(),
+                scope: Scope::Admin,
+            }],
+        )
+        .await?;
+        let state = AppState::new(pool, Cache::new(64))
+            .await
+            .with_auth(Auth::new(Duration::from_secs(60)))
+            .with_rate_limit(RateLimiter::new(Some(Budget::new(0.1, Some(2.0))), None));
+        let app = setup_test_app_with_state(state).await;
+        let client = "10.0.0.1:4000".parse().unwrap();
+
+        // every attempt uses a new token, none of which is remembered
+        for status in [
+            StatusCode::UNAUTHORIZED,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/key_1")
+                .peer_addr(client)
+                .insert_header((
+                    header::AUTHORIZATION,
+                    format!("Bearer {}", auth::generate_token()),
+                ))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+        }
+
+        // authenticated clients have budgets of their own
+        let req = test::TestRequest::get()
+            .uri("/stats")
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin)))
+            .to_request();
+        let stats: RateLimitStats = test::call_and_read_body_json(&app, req).await;
+        let usage = stats.clients["ip:10.0.0.1"];
+        assert_eq!((usage.reads, usage.limited), (4, 2));
+        assert_eq!(stats.clients["key:admin"].reads, 1);
+
+        Ok(())
+    }
+}
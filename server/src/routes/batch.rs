@@ -0,0 +1,435 @@
+use crate::cache::{CacheEntry, CacheKey};
+use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::{validate_key, NamespacePath};
+use crate::routes::post;
+use crate::state::AppState;
+use crate::store::{self, TEXT_CONTENT_TYPE};
+use crate::value::JsonValue;
+use crate::write_back::{self, Scope};
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::{Json, Path};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+fn validate_keys(keys: &[String]) -> Result<(), ValidationError> {
+    keys.iter().try_for_each(|key| validate_key(key))
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct GetRequest {
+    #[validate(
+        length(min = 1, max = 1000, message = "invalid number of keys"),
+        custom(function = "validate_keys")
+    )]
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GetResponse {
+    found: BTreeMap<String, JsonValue>,
+    missing: Vec<String>,
+}
+
+#[routes]
+#[post("/_batch/get")]
+#[post("/ns/{namespace}/_batch/get")]
+async fn batch_get(
+    path: Path<NamespacePath>,
+    payload: Json<GetRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let mut seen = HashSet::with_capacity(payload.keys.len());
+    let mut found = BTreeMap::new();
+    let mut misses = Vec::new();
+    let mut missing = Vec::new();
+
+    for key in payload.into_inner().keys {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+
+        let cache_key = CacheKey::new(path.namespace.clone(), key.clone());
+        // a pending write shadows whatever the cache and the database hold
+        if let Some(entry) = data.write_back.get(&cache_key) {
+            if entry.is_expired() {
+                missing.push(key);
+            } else {
+                found.insert(key, JsonValue::from(entry.value.as_ref()));
+            }
+            continue;
+        }
+
+        match data.cache.get(&cache_key).await {
+            Some(entry) => {
+                found.insert(key, JsonValue::from(entry.value.as_ref()));
+            }
+            None => misses.push(key),
+        }
+    }
+
+    if !misses.is_empty() {
+        let rows = sqlx::query!(
+            r#"
+SELECT key, value, content_type, version, expires_at
+FROM kv_store
+WHERE namespace = $1
+  AND key = ANY($2)
+  AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+            path.namespace,
+            &misses
+        )
+        .fetch_all(&data.db_pool)
+        .await?;
+
+        for row in rows {
+            found.insert(row.key.clone(), JsonValue::from(row.value.as_slice()));
+            data.cache
+                .insert(
+                    CacheKey::new(path.namespace.clone(), row.key),
+                    CacheEntry::new(row.value, row.content_type, row.version, row.expires_at),
+                )
+                .await;
+        }
+    }
+
+    missing.extend(misses.into_iter().filter(|key| !found.contains_key(key)));
+
+    Ok(HttpResponse::Ok().json(GetResponse { found, missing }))
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct DeleteRequest {
+    #[validate(custom(function = "validate_key"))]
+    key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Put(post::Request),
+    Delete(DeleteRequest),
+}
+
+impl Operation {
+    fn key(&self) -> &str {
+        match self {
+            Operation::Put(req) => &req.key,
+            Operation::Delete(req) => &req.key,
+        }
+    }
+}
+
+impl Validate for Operation {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        match self {
+            Operation::Put(req) => req.validate(),
+            Operation::Delete(req) => req.validate(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct WriteRequest {
+    #[validate(
+        length(min = 1, max = 1000, message = "invalid number of operations"),
+        nested
+    )]
+    ops: Vec<Operation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Created,
+    Updated,
+    Deleted,
+    NotFound,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OperationResult {
+    key: String,
+    result: Outcome,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WriteResponse {
+    results: Vec<OperationResult>,
+}
+
+/// Applies every operation in a single transaction; either all of them are committed or none are.
+#[routes]
+#[post("/_batch/write")]
+#[post("/ns/{namespace}/_batch/write")]
+async fn batch_write(
+    path: Path<NamespacePath>,
+    payload: Json<WriteRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let ops = payload.into_inner().ops;
+    let mut results = Vec::with_capacity(ops.len());
+    let mut entries = Vec::with_capacity(ops.len());
+    // `NOW()` is fixed for the whole transaction, so a pair written twice in the same batch would
+    // be reported as created both times without tracking it here
+    let mut written = HashMap::new();
+
+    write_back::flush(&data, Scope::Namespace(&path.namespace)).await?;
+    let mut tx = data.db_pool.begin().await?;
+
+    for op in &ops {
+        let outcome = match op {
+            Operation::Put(req) => {
+                let write = store::Write {
+                    namespace: &path.namespace,
+                    key: &req.key,
+                    value: req.value.as_bytes(),
+                    content_type: TEXT_CONTENT_TYPE,
+                    ttl_seconds: req.ttl_seconds,
+                };
+                let row = store::upsert(&mut *tx, &write).await?;
+                journal::record_put(
+                    &mut tx,
+                    data.history,
+                    &journal::Put {
+                        namespace: write.namespace,
+                        key: write.key,
+                        version: row.version,
+                        value: write.value,
+                        content_type: write.content_type,
+                        expires_at: row.expires_at,
+                    },
+                )
+                .await?;
+                let existed = written.insert(req.key.as_str(), true).unwrap_or(false);
+                entries.push(Some(CacheEntry::new(
+                    req.value.clone(),
+                    TEXT_CONTENT_TYPE,
+                    row.version,
+                    row.expires_at,
+                )));
+
+                if row.inserted && !existed {
+                    Outcome::Created
+                } else {
+                    Outcome::Updated
+                }
+            }
+            Operation::Delete(req) => {
+                let deleted = store::delete(&mut *tx, &path.namespace, &req.key).await?;
+                if deleted {
+                    journal::record_delete(&mut tx, data.history, &path.namespace, &req.key)
+                        .await?;
+                }
+                written.insert(req.key.as_str(), false);
+                entries.push(None);
+
+                if deleted {
+                    Outcome::Deleted
+                } else {
+                    Outcome::NotFound
+                }
+            }
+        };
+
+        results.push(OperationResult {
+            key: op.key().to_string(),
+            result: outcome,
+        });
+    }
+
+    let invalidations: Vec<_> = ops
+        .iter()
+        .map(|op| Invalidation::key(&data.instance_id, &path.namespace, op.key()))
+        .collect();
+    invalidation::publish(&mut *tx, &invalidations).await?;
+    tx.commit().await?;
+
+    // the cache only ever sees committed values
+    for (op, entry) in ops.iter().zip(entries) {
+        let key = CacheKey::new(path.namespace.clone(), op.key());
+        match entry {
+            Some(entry) => data.cache.write(key, entry).await,
+            None => data.cache.remove(&key).await,
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(WriteResponse { results }))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(batch_get);
+    cfg.service(batch_write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GetResponse, Outcome, WriteResponse};
+    use crate::cache::CacheStats;
+    use crate::test_utils::setup_app::setup_test_app;
+    use crate::value::JsonValue;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_get_many_keys(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        sqlx::query!(
+            "INSERT INTO kv_store (key, value) VALUES ($1, $2)",
+            "key_1",
+            "value_1".as_bytes()
+        )
+        .execute(&pool)
+        .await?;
+
+        // populates the cache
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_2", "value": "value_2"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/_batch/get")
+            .set_json(json!({"keys": ["key_1", "key_2", "key_3", "key_1"]}))
+            .to_request();
+        let res: GetResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.found.len(), 2);
+        assert_eq!(res.found["key_1"], JsonValue::Text("value_1".into()));
+        assert_eq!(res.found["key_2"], JsonValue::Text("value_2".into()));
+        assert_eq!(res.missing, vec!["key_3"]);
+
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let stats: CacheStats = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+
+        // misses fetched from the database are now cached
+        let req = test::TestRequest::post()
+            .uri("/_batch/get")
+            .set_json(json!({"keys": ["key_1", "key_2"]}))
+            .to_request();
+        let res: GetResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.found.len(), 2);
+
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let stats: CacheStats = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats.hits, 3);
+
+        let req = test::TestRequest::post()
+            .uri("/_batch/get")
+            .set_json(json!({"keys": []}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // every key is validated like a single one
+        for key in ["key\u{0}".to_string(), "k".repeat(513)] {
+            let req = test::TestRequest::post()
+                .uri("/_batch/get")
+                .set_json(json!({"keys": ["key_1", key]}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn can_write_many_keys(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_batch/write")
+            .set_json(json!({"ops": [
+                {"op": "put", "key": "key_1", "value": "value_1"},
+                {"op": "put", "key": "key_2", "value": "value_2"},
+                {"op": "delete", "key": "key_3"},
+                {"op": "put", "key": "key_1", "value": "value_3"},
+                {"op": "delete", "key": "key_2"},
+            ]}))
+            .to_request();
+        let res: WriteResponse = test::call_and_read_body_json(&app, req).await;
+        let outcomes: Vec<_> = res.results.iter().map(|result| result.result).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Created,
+                Outcome::Created,
+                Outcome::NotFound,
+                Outcome::Updated,
+                Outcome::Deleted
+            ]
+        );
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "value_3");
+
+        let req = test::TestRequest::get().uri("/key_2").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn batch_write_is_all_or_nothing(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        // fails validation before anything is written
+        let req = test::TestRequest::post()
+            .uri("/_batch/write")
+            .set_json(json!({"ops": [
+                {"op": "put", "key": "key_1", "value": "value_1"},
+                {"op": "put", "key": "key_2", "value": ""},
+            ]}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // keys postgres could not store are rejected upfront too
+        let req = test::TestRequest::post()
+            .uri("/_batch/write")
+            .set_json(json!({"ops": [
+                {"op": "put", "key": "key_1", "value": "value_1"},
+                {"op": "put", "key": "key_\u{0}", "value": "value_2"},
+            ]}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // a constraint fails the second write inside the transaction
+        sqlx::query("ALTER TABLE kv_store ADD CONSTRAINT no_key_2 CHECK (key <> 'key_2')")
+            .execute(&pool)
+            .await?;
+        let req = test::TestRequest::post()
+            .uri("/_batch/write")
+            .set_json(json!({"ops": [
+                {"op": "put", "key": "key_1", "value": "value_1"},
+                {"op": "put", "key": "key_2", "value": "value_2"},
+            ]}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let count = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM kv_store"#)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(count, 0);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use crate::changes::{self, Consumer, Filter, Op};
+use crate::error::AppError;
+use crate::namespace::NamespacePath;
+use crate::state::AppState;
+use crate::value::JsonValue;
+use actix_web::{delete, get, put, routes, web, HttpResponse};
+use actix_web_validator::{Json, Path, Query};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize, Validate)]
+struct Params {
+    #[serde(default)]
+    #[validate(range(min = 0, message = "invalid sequence number"))]
+    after: i64,
+    #[validate(range(min = 1, max = 1000, message = "invalid limit"))]
+    limit: Option<i64>,
+    #[serde(default)]
+    values: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Item {
+    seq: i64,
+    op: Op,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    changed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChangesResponse {
+    changes: Vec<Item>,
+    /// passed back as `after` to fetch the next page
+    next_after: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+struct ConsumerPath {
+    #[validate(length(min = 1, max = 64, message = "invalid consumer name length"))]
+    name: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+struct AckRequest {
+    #[validate(range(min = 0, message = "invalid sequence number"))]
+    seq: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConsumersResponse {
+    consumers: Vec<Consumer>,
+    /// changes up to this sequence number have been compacted away
+    compacted_through: i64,
+}
+
+/// Lists the changes made to a namespace after the sequence number `after`, in order.
+///
+/// Fails with `410 Gone` if changes after `after` have already been compacted away, as they can no
+/// longer be returned.
+#[routes]
+#[get("/_changes")]
+#[get("/ns/{namespace}/_changes")]
+async fn list_changes(
+    path: Path<NamespacePath>,
+    params: Query<Params>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let compacted_through = changes::compacted_through(&data.db_pool).await?;
+    if params.after < compacted_through {
+        return Err(AppError::Gone(format!(
+            "changes up to {} have been compacted",
+            compacted_through
+        )));
+    }
+
+    let filter = Filter {
+        namespace: Some(path.into_inner().namespace),
+        ..Filter::default()
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    // changes past it may still be joined by earlier ones
+    let latest = changes::latest_seq(&data.db_pool).await?;
+    let changes = changes::list(&data.db_pool, &filter, params.after, Some(latest), limit).await?;
+
+    let next_after = changes.last().map_or(params.after, |change| change.seq);
+    let changes = changes
+        .into_iter()
+        .map(|change| Item {
+            seq: change.seq,
+            op: change.op,
+            key: change.key,
+            version: change.version,
+            value: change
+                .value
+                .filter(|_| params.values)
+                .map(|value| JsonValue::from(value.as_slice())),
+            content_type: change.content_type,
+            changed_at: change.changed_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ChangesResponse {
+        changes,
+        next_after,
+    }))
+}
+
+#[get("/_changes/consumers")]
+async fn list_consumers(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(ConsumersResponse {
+        consumers: changes::consumers(&data.db_pool).await?,
+        compacted_through: changes::compacted_through(&data.db_pool).await?,
+    }))
+}
+
+/// Acknowledges every change up to `seq`, allowing the log to be compacted up to the lowest
+/// checkpoint of all consumers.
+#[put("/_changes/consumers/{name}")]
+async fn acknowledge(
+    path: Path<ConsumerPath>,
+    payload: Json<AckRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    // a checkpoint ahead of the log would let changes be compacted before they are consumed
+    if payload.seq > changes::latest_seq(&data.db_pool).await? {
+        return Err(AppError::BadRequest(format!(
+            "sequence number {} has not been reached yet",
+            payload.seq
+        )));
+    }
+
+    let consumer = changes::acknowledge(&data.db_pool, &path.name, payload.seq).await?;
+
+    Ok(HttpResponse::Ok().json(consumer))
+}
+
+/// Unregisters a consumer, so that it no longer holds back compaction.
+#[delete("/_changes/consumers/{name}")]
+async fn remove_consumer(
+    path: Path<ConsumerPath>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if !changes::remove_consumer(&data.db_pool, &path.name).await? {
+        return Err(AppError::NotFound(path.name.clone()));
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_changes);
+    cfg.service(list_consumers);
+    cfg.service(acknowledge);
+    cfg.service(remove_consumer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChangesResponse;
+    use crate::changes::{compact, Op};
+    use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn can_read_and_compact_changes(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        for value in ["value_1", "value_2"] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": "key_1", "value": value}))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::delete().uri("/key_1").to_request();
+        test::call_service(&app, req).await;
+
+        // paginate
+        let req = test::TestRequest::get()
+            .uri("/_changes?limit=2&values=true")
+            .to_request();
+        let page: ChangesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page.changes.len(), 2);
+        assert!(page.changes.iter().all(|change| change.op == Op::Put));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/_changes?after={}", page.next_after))
+            .to_request();
+        let rest: ChangesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(rest.changes.len(), 1);
+        assert_eq!(rest.changes[0].op, Op::Delete);
+
+        // other namespaces are not listed
+        let req = test::TestRequest::get()
+            .uri("/ns/ns_1/_changes")
+            .to_request();
+        let other: ChangesResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(other.changes.is_empty());
+
+        // acknowledge and compact
+        let req = test::TestRequest::put()
+            .uri("/_changes/consumers/indexer")
+            .set_json(json!({"seq": page.next_after}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(compact(&pool, 100, Duration::ZERO).await?, Some(2));
+
+        let req = test::TestRequest::get().uri("/_changes").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/_changes?after={}", page.next_after))
+            .to_request();
+        let rest: ChangesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(rest.changes.len(), 1);
+
+        Ok(())
+    }
+}
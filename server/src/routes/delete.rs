@@ -1,30 +1,62 @@
 use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::KeyPath;
+use crate::precondition::Precondition;
 use crate::state::AppState;
-use actix_web::{delete, web, HttpResponse};
-use serde::Deserialize;
-
-#[derive(Deserialize)]
-struct Fragments {
-    key: String,
-}
+use crate::store;
+use crate::write_back::{self, Scope};
+use actix_web::{routes, web, HttpRequest, HttpResponse};
+use actix_web_validator::Path;
 
+/// Honors `If-Match`, deleting the pair only if it is still at the expected version.
+#[routes]
 #[delete("/{key}")]
+#[delete("/ns/{namespace}/{key}")]
 async fn get_kv(
-    path: web::Path<Fragments>,
+    req: HttpRequest,
+    path: Path<KeyPath>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
     let key = path.key.clone();
-    match sqlx::query!("DELETE FROM kv_store WHERE key = $1", key)
-        .execute(&data.db_pool)
-        .await?
-        .rows_affected()
-    {
-        0 => Err(AppError::NotFound(key)),
-        _ => {
-            data.cache.remove(&key).await;
-            Ok(HttpResponse::Ok().finish())
+    write_back::flush(&data, Scope::Key(&path.cache_key())).await?;
+    let mut tx = data.db_pool.begin().await?;
+
+    // without a precondition a missing pair is simply not found, with one it fails the condition
+    let (deleted, err) = match Precondition::from_request(&req)? {
+        Precondition::None => (
+            store::delete(&mut *tx, &path.namespace, &key).await?,
+            AppError::NotFound(key.clone()),
+        ),
+        Precondition::Exists => (
+            store::delete(&mut *tx, &path.namespace, &key).await?,
+            AppError::PreconditionFailed(key.clone()),
+        ),
+        Precondition::Matches(versions) => (
+            store::delete_if_matches(&mut *tx, &path.namespace, &key, &versions).await?,
+            AppError::PreconditionFailed(key.clone()),
+        ),
+        Precondition::Absent => {
+            return Err(AppError::BadRequest(
+                "If-None-Match is not supported on deletes".into(),
+            ));
         }
+    };
+
+    if !deleted {
+        return Err(err);
     }
+    journal::record_delete(&mut tx, data.history, &path.namespace, &key).await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::key(&data.instance_id, &path.namespace, &key)],
+    )
+    .await?;
+    tx.commit().await?;
+
+    data.cache.remove(&path.cache_key()).await;
+
+    Ok(HttpResponse::Ok().finish())
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
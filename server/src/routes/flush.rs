@@ -1,16 +1,35 @@
 use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::NamespacePath;
 use crate::state::AppState;
-use actix_web::{post, web, HttpResponse};
+use crate::store;
+use crate::write_back::{self, Scope};
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::Path;
 
+/// Deletes every pair of a single namespace, the default one unless named.
+#[routes]
 #[post("/flush")]
-async fn flush_kv(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
-    let result = sqlx::query!(r#"DELETE FROM kv_store"#)
-        .execute(&data.db_pool)
-        .await?;
+#[post("/ns/{namespace}/flush")]
+async fn flush_kv(
+    path: Path<NamespacePath>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    write_back::flush(&data, Scope::Namespace(&path.namespace)).await?;
+    let mut tx = data.db_pool.begin().await?;
+    journal::record_flush(&mut tx, data.history, &path.namespace).await?;
+    let flushed = store::flush(&mut *tx, &path.namespace).await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::namespace(&data.instance_id, &path.namespace)],
+    )
+    .await?;
+    tx.commit().await?;
 
-    data.cache.flush();
+    data.cache.flush_namespace(&path.namespace);
 
-    Ok(HttpResponse::Ok().body(format!("flushed {} pairs", result.rows_affected())))
+    Ok(HttpResponse::Ok().body(format!("flushed {} pairs", flushed)))
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
@@ -52,4 +71,42 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn flush_is_scoped_to_namespace(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        for namespace in ["ns_1", "ns_2"] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/ns/{}", namespace))
+                .set_json(json!({"key": "key_1", "value": namespace}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+
+        // same key, isolated values
+        let req = test::TestRequest::get().uri("/ns/ns_2/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "ns_2");
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // flush
+        let req = test::TestRequest::post().uri("/ns/ns_1/flush").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "flushed 1 pairs");
+
+        let req = test::TestRequest::get().uri("/ns/ns_1/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get().uri("/ns/ns_2/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "ns_2");
+
+        Ok(())
+    }
 }
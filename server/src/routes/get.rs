@@ -1,36 +1,104 @@
+use crate::cache::CacheEntry;
 use crate::error::AppError;
+use crate::history;
+use crate::namespace::KeyPath;
+use crate::precondition::etag;
 use crate::state::AppState;
-use actix_web::{get, web, HttpResponse};
+use crate::write_back::{self, Scope};
+use actix_web::http::header::ETag;
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::{Path, Query};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use validator::Validate;
 
-#[derive(Deserialize)]
-struct Fragments {
-    key: String,
+#[derive(Debug, Deserialize, Validate)]
+struct Params {
+    /// reads the value current at this time from history instead
+    as_of: Option<DateTime<Utc>>,
 }
 
+#[routes]
 #[get("/{key}")]
+#[get("/ns/{namespace}/{key}")]
 async fn get_kv(
-    path: web::Path<Fragments>,
+    path: Path<KeyPath>,
+    params: Query<Params>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let key = path.key.clone();
+    if let Some(at) = params.as_of {
+        return get_as_of(&path, at, &data).await;
+    }
+
+    let cache_key = path.cache_key();
 
-    if let Some(value) = data.cache.get(&key).await {
-        return Ok(HttpResponse::Ok().body(value));
+    // a pending write shadows whatever the cache and the database hold
+    if let Some(entry) = data.write_back.get(&cache_key) {
+        if entry.is_expired() {
+            return Err(AppError::NotFound(path.key.clone()));
+        }
+        return Ok(respond(entry));
     }
 
-    let value = sqlx::query!("SELECT value FROM kv_store WHERE key = $1", key)
-        .fetch_one(&data.db_pool)
-        .await
-        .map_err(|err| match err {
-            sqlx::Error::RowNotFound => AppError::NotFound(key.clone()),
-            other => AppError::Database(other),
-        })?
-        .value;
+    // concurrent misses of the same key share a single query
+    let entry = data
+        .cache
+        .get_or_load(&cache_key, async {
+            let row = sqlx::query!(
+                r#"
+SELECT value, content_type, version, expires_at
+FROM kv_store
+WHERE namespace = $1
+  AND key = $2
+  AND (expires_at IS NULL OR expires_at > NOW())
+                "#,
+                path.namespace,
+                path.key
+            )
+            .fetch_optional(&data.db_pool)
+            .await?;
+
+            Ok(row.map(|row| {
+                CacheEntry::new(row.value, row.content_type, row.version, row.expires_at)
+            }))
+        })
+        .await?
+        .ok_or_else(|| AppError::NotFound(path.key.clone()))?;
+
+    Ok(respond(entry))
+}
+
+fn respond(entry: CacheEntry) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(entry.content_type)
+        .insert_header(ETag(etag(entry.version)))
+        .body(entry.value)
+}
+
+/// Reads the value of a pair at a point in time, bypassing the cache.
+async fn get_as_of(
+    path: &KeyPath,
+    at: DateTime<Utc>,
+    data: &AppState,
+) -> Result<HttpResponse, AppError> {
+    if !data.history {
+        return Err(AppError::BadRequest("history is not enabled".into()));
+    }
+    write_back::flush(data, Scope::Key(&path.cache_key())).await?;
 
-    data.cache.insert(key, value.clone()).await;
+    let version = history::as_of(&data.db_pool, &path.namespace, &path.key, at)
+        .await?
+        .filter(|version| version.expires_at.is_none_or(|expires_at| expires_at > at))
+        .ok_or_else(|| AppError::NotFound(path.key.clone()))?;
+    // deletes carry neither a value nor a content type
+    let (Some(value), Some(content_type)) = (version.value, version.content_type) else {
+        return Err(AppError::NotFound(path.key.clone()));
+    };
 
-    Ok(HttpResponse::Ok().body(value))
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ETag(etag(version.version)))
+        .body(value))
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
@@ -40,6 +108,7 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod tests {
     use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::http::StatusCode;
     use actix_web::test;
     use sqlx::PgPool;
 
@@ -50,7 +119,7 @@ mod tests {
         sqlx::query!(
             "INSERT INTO kv_store (key, value) VALUES ($1, $2)",
             "key_1",
-            "value_1"
+            "value_1".as_bytes()
         )
         .execute(&pool)
         .await?;
@@ -67,4 +136,23 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn expired_key_is_not_found(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        sqlx::query!(
+            "INSERT INTO kv_store (key, value, expires_at) VALUES ($1, $2, NOW() - INTERVAL '1 second')",
+            "key_1",
+            "value_1".as_bytes()
+        )
+        .execute(&pool)
+        .await?;
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,95 @@
+use crate::health::Status;
+use crate::state::AppState;
+use actix_web::{get, web, HttpResponse};
+use serde_json::json;
+
+/// Succeeds as long as the process serves requests.
+#[get("/_health/live")]
+async fn live() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status": Status::Up}))
+}
+
+/// Succeeds if this instance can serve traffic: the database answers, its migrations are applied
+/// and the instance is not shutting down. Fails with `503 Service Unavailable` otherwise.
+/// Dependencies are checked at most once a second, however often this is called.
+#[get("/_health/ready")]
+async fn ready(data: web::Data<AppState>) -> HttpResponse {
+    let readiness = data.health.readiness(&data.db_pool).await;
+    match readiness.status {
+        Status::Up => HttpResponse::Ok().json(readiness),
+        Status::Down => HttpResponse::ServiceUnavailable().json(readiness),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(live);
+    cfg.service(ready);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::Cache;
+    use crate::health::{Health, Readiness, Status};
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::setup_test_app_with_state;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn readiness_fails_while_draining(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool, Cache::new(64)).await;
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        let req = test::TestRequest::get().uri("/_health/live").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/_health/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let readiness: Readiness = test::read_body_json(res).await;
+        assert_eq!(readiness.status, Status::Up);
+        assert!(readiness.components["database"].latency_ms.is_some());
+        assert_eq!(readiness.components["migrations"].status, Status::Up);
+
+        state.health.start_draining();
+        let req = test::TestRequest::get().uri("/_health/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: Readiness = test::read_body_json(res).await;
+        assert_eq!(readiness.components["shutdown"].status, Status::Down);
+        assert_eq!(readiness.components["database"].status, Status::Up);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn readiness_is_cached_and_hides_errors(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool.clone(), Cache::new(64)).await;
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        let req = test::TestRequest::get().uri("/_health/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        sqlx::query("ALTER TABLE _sqlx_migrations RENAME TO _sqlx_migrations_old")
+            .execute(&pool)
+            .await?;
+
+        // the last check is reused for a while
+        let req = test::TestRequest::get().uri("/_health/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let app = setup_test_app_with_state(state.with_health(Health::default())).await;
+        let req = test::TestRequest::get().uri("/_health/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: Readiness = test::read_body_json(res).await;
+        let migrations = &readiness.components["migrations"];
+        assert_eq!(migrations.error.as_deref(), Some("check failed"));
+
+        Ok(())
+    }
+}
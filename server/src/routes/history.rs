@@ -0,0 +1,225 @@
+use crate::cache::CacheEntry;
+use crate::error::AppError;
+use crate::history;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::KeyPath;
+use crate::precondition::{etag, Precondition};
+use crate::state::AppState;
+use crate::store;
+use crate::value::JsonValue;
+use crate::write_back::{self, Scope};
+use actix_web::http::header::ETag;
+use actix_web::{routes, web, HttpRequest, HttpResponse};
+use actix_web_validator::{Json, Path, Query};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Validate)]
+struct Params {
+    #[serde(default)]
+    values: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Item {
+    version: i64,
+    deleted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonValue>,
+    content_type: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryResponse {
+    versions: Vec<Item>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+struct RestoreRequest {
+    version: i64,
+    #[validate(range(min = 1, message = "invalid ttl"))]
+    ttl_seconds: Option<i32>,
+}
+
+/// Lists the recorded versions of a pair, newest first.
+#[routes]
+#[get("/_history/{key}")]
+#[get("/ns/{namespace}/_history/{key}")]
+async fn list_history(
+    path: Path<KeyPath>,
+    params: Query<Params>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    write_back::flush(&data, Scope::Key(&path.cache_key())).await?;
+    let versions = history::list(&data.db_pool, &path.namespace, &path.key)
+        .await?
+        .into_iter()
+        .map(|version| Item {
+            version: version.version,
+            deleted: version.value.is_none(),
+            value: version
+                .value
+                .filter(|_| params.values)
+                .map(|value| JsonValue::from(value.as_slice())),
+            content_type: version.content_type,
+            expires_at: version.expires_at,
+            recorded_at: version.recorded_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(HistoryResponse { versions }))
+}
+
+/// Writes a recorded version back as the current value, under a new version. The restored pair
+/// never expires unless `ttl_seconds` is given. Honors the same preconditions as `POST /`.
+#[routes]
+#[post("/_history/{key}/restore")]
+#[post("/ns/{namespace}/_history/{key}/restore")]
+async fn restore_version(
+    req: HttpRequest,
+    path: Path<KeyPath>,
+    payload: Json<RestoreRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if !data.history {
+        return Err(AppError::BadRequest("history is not enabled".into()));
+    }
+
+    let precondition = Precondition::from_request(&req)?;
+    write_back::flush(&data, Scope::Key(&path.cache_key())).await?;
+    let mut tx = data.db_pool.begin().await?;
+
+    let version = history::find(&mut *tx, &path.namespace, &path.key, payload.version)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!("{} at version {}", path.key, payload.version))
+        })?;
+    let (Some(value), Some(content_type)) = (version.value, version.content_type) else {
+        return Err(AppError::BadRequest(format!(
+            "version {} of {} is a delete",
+            payload.version, path.key
+        )));
+    };
+
+    let write = store::Write {
+        namespace: &path.namespace,
+        key: &path.key,
+        value: &value,
+        content_type: &content_type,
+        ttl_seconds: payload.ttl_seconds,
+    };
+    let row = store::upsert_if(&mut *tx, &write, &precondition)
+        .await?
+        .ok_or_else(|| AppError::PreconditionFailed(path.key.clone()))?;
+    journal::record_put(
+        &mut tx,
+        data.history,
+        &journal::Put {
+            namespace: write.namespace,
+            key: write.key,
+            version: row.version,
+            value: write.value,
+            content_type: write.content_type,
+            expires_at: row.expires_at,
+        },
+    )
+    .await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::key(
+            &data.instance_id,
+            write.namespace,
+            write.key,
+        )],
+    )
+    .await?;
+    tx.commit().await?;
+
+    data.cache
+        .write(
+            path.cache_key(),
+            CacheEntry::new(value, content_type, row.version, row.expires_at),
+        )
+        .await;
+
+    Ok(if row.inserted {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::NoContent()
+    }
+    .insert_header(ETag(etag(row.version)))
+    .finish())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_history);
+    cfg.service(restore_version);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryResponse;
+    use crate::test_utils::setup_app::setup_test_app;
+    use crate::value::JsonValue;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use chrono::{SecondsFormat, Utc};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_restore_previous_version(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        for value in ["value_1", "value_2"] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": "key_1", "value": value}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(res.status().is_success());
+        }
+        let before_delete = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+
+        let req = test::TestRequest::delete().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        // list
+        let req = test::TestRequest::get()
+            .uri("/_history/key_1?values=true")
+            .to_request();
+        let res: HistoryResponse = test::call_and_read_body_json(&app, req).await;
+        let deleted: Vec<_> = res.versions.iter().map(|item| item.deleted).collect();
+        assert_eq!(deleted, vec![true, false, false]);
+        assert_eq!(
+            res.versions[2].value,
+            Some(JsonValue::Text("value_1".into()))
+        );
+
+        // read as of a point in time
+        let req = test::TestRequest::get()
+            .uri(&format!("/key_1?as_of={}", before_delete))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&body).unwrap(), "value_2");
+
+        // restore
+        let req = test::TestRequest::post()
+            .uri("/_history/key_1/restore")
+            .set_json(json!({"version": res.versions[2].version}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&body).unwrap(), "value_1");
+
+        Ok(())
+    }
+}
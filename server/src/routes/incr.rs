@@ -0,0 +1,167 @@
+use crate::cache::CacheEntry;
+use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::KeyPath;
+use crate::precondition::etag;
+use crate::state::AppState;
+use crate::store::{self, TEXT_CONTENT_TYPE};
+use crate::write_back::{self, Scope};
+use actix_web::http::header::ETag;
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::{Json, Path};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct Request {
+    #[serde(default = "default_delta")]
+    delta: i64,
+}
+
+fn default_delta() -> i64 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    value: i64,
+}
+
+/// Atomically adds a signed `delta` (1 by default) to an integer pair and returns the new value.
+#[routes]
+#[post("/_incr/{key}")]
+#[post("/ns/{namespace}/_incr/{key}")]
+async fn incr_kv(
+    path: Path<KeyPath>,
+    payload: Json<Request>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let key = path.key.clone();
+    write_back::flush(&data, Scope::Key(&path.cache_key())).await?;
+    let mut tx = data.db_pool.begin().await?;
+    let row = store::increment(&mut *tx, &path.namespace, &key, payload.delta)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(ref db_err) => match db_err.code().as_deref() {
+                // invalid_text_representation, character_not_in_repertoire
+                Some("22P02" | "22021") => {
+                    AppError::BadRequest(format!("value of {} is not an integer", key))
+                }
+                // numeric_value_out_of_range
+                Some("22003") => AppError::BadRequest(format!("value of {} would overflow", key)),
+                _ => AppError::Database(err),
+            },
+            other => AppError::Database(other),
+        })?;
+    journal::record_put(
+        &mut tx,
+        data.history,
+        &journal::Put {
+            namespace: &path.namespace,
+            key: &key,
+            version: row.version,
+            value: &row.value,
+            content_type: TEXT_CONTENT_TYPE,
+            expires_at: row.expires_at,
+        },
+    )
+    .await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::key(&data.instance_id, &path.namespace, &key)],
+    )
+    .await?;
+    tx.commit().await?;
+
+    let value = str::from_utf8(&row.value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| AppError::Internal(format!("non-integer result for {}", key)))?;
+
+    data.cache
+        .write(
+            path.cache_key(),
+            CacheEntry::new(row.value, TEXT_CONTENT_TYPE, row.version, row.expires_at),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ETag(etag(row.version)))
+        .json(Response { value }))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(incr_kv);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+    use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_increment_key(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        // created when missing
+        let req = test::TestRequest::post()
+            .uri("/_incr/key_1")
+            .set_json(json!({"delta": 5}))
+            .to_request();
+        let res: Response = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.value, 5);
+
+        let req = test::TestRequest::post()
+            .uri("/_incr/key_1")
+            .set_json(json!({"delta": -7}))
+            .to_request();
+        let res: Response = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.value, -2);
+
+        let req = test::TestRequest::post()
+            .uri("/_incr/key_1")
+            .set_json(json!({}))
+            .to_request();
+        let res: Response = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.value, -1);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "-1");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rejects_non_integer_values(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        sqlx::query!(
+            "INSERT INTO kv_store (key, value) VALUES ('key_1', 'value_1'), ('key_2', $1)",
+            i64::MAX.to_string().into_bytes()
+        )
+        .execute(&pool)
+        .await?;
+
+        let req = test::TestRequest::post()
+            .uri("/_incr/key_1")
+            .set_json(json!({"delta": 1}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/_incr/key_2")
+            .set_json(json!({"delta": 1}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use crate::auth::{self, ApiKey, Grant};
+use crate::error::AppError;
+use crate::state::AppState;
+use actix_web::{delete, get, post, web, HttpResponse};
+use actix_web_validator::{Json, Path};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct CreateRequest {
+    #[validate(length(min = 1, max = 64, message = "invalid key name length"))]
+    name: String,
+    #[validate(length(min = 1, message = "at least one grant is required"), nested)]
+    grants: Vec<Grant>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Created {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// only ever returned here, as just its hash is stored
+    token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeysResponse {
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+struct KeyNamePath {
+    #[validate(length(min = 1, max = 64, message = "invalid key name length"))]
+    name: String,
+}
+
+#[get("/_keys")]
+async fn list_keys(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(KeysResponse {
+        keys: auth::list(&data.db_pool).await?,
+    }))
+}
+
+/// Creates a key with a new token, returned once.
+#[post("/_keys")]
+async fn create_key(
+    payload: Json<CreateRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let token = auth::generate_token();
+    let key = auth::create(&data.db_pool, &payload.name, &token, &payload.grants)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("key {} already exists", payload.name)))?;
+    data.auth.forget();
+
+    Ok(HttpResponse::Created().json(Created { key, token }))
+}
+
+/// Revokes a key.
+#[delete("/_keys/{name}")]
+async fn remove_key(
+    path: Path<KeyNamePath>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if !auth::remove(&data.db_pool, &path.name).await? {
+        return Err(AppError::NotFound(path.name.clone()));
+    }
+    data.auth.forget();
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_keys);
+    cfg.service(create_key);
+    cfg.service(remove_key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Created, KeysResponse};
+    use crate::auth::{self, Auth, Grant, Scope};
+    use crate::cache::Cache;
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::setup_test_app_with_state;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn keys_are_scoped_per_namespace(pool: PgPool) -> sqlx::Result<()> {
+        let admin = auth::generate_token();
+        auth::replace(
+            &pool,
+            "admin",
+            &admin,
+            &[Grant {
+                namespace: "*".into(),
+                scope: Scope::Admin,
+            }],
+        )
+        .await?;
+        let state = AppState::new(pool.clone(), Cache::new(64))
+            .await
+            .with_auth(Auth::new(Duration::from_secs(60)));
+        let app = setup_test_app_with_state(state).await;
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        let req = test::TestRequest::get().uri("/_health/live").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // create a key reading every namespace and writing one
+        let req = test::TestRequest::post()
+            .uri("/_keys")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin)))
+            .set_json(json!({
+                "name": "writer",
+                "grants": [
+                    {"namespace": "ns_1", "scope": "write"},
+                    {"namespace": "*", "scope": "read"},
+                ],
+            }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: Created = test::read_body_json(res).await;
+        let bearer = format!("Bearer {}", created.token);
+
+        for (method, uri, status) in [
+            ("POST", "/ns/ns_1", StatusCode::CREATED),
+            // namespaces are authorized as routed, percent-decoded: this updates the pair of ns_1
+            ("POST", "/ns/%6Es_1", StatusCode::NO_CONTENT),
+            ("POST", "/ns/%6Es_2", StatusCode::FORBIDDEN),
+            ("GET", "/ns/ns_1/key_1", StatusCode::OK),
+            ("POST", "/ns/ns_2", StatusCode::FORBIDDEN),
+            ("GET", "/ns/ns_2/key_1", StatusCode::NOT_FOUND),
+            ("POST", "/ns/ns_1/flush", StatusCode::FORBIDDEN),
+            ("GET", "/_keys", StatusCode::FORBIDDEN),
+        ] {
+            let req = test::TestRequest::default()
+                .method(method.parse().unwrap())
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, bearer.clone()))
+                .set_json(json!({"key": "key_1", "value": "value_1"}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{} {}", method, uri);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/_keys")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin)))
+            .to_request();
+        let keys: KeysResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(keys.keys.len(), 2);
+
+        // revoked keys are rejected right away
+        let req = test::TestRequest::delete()
+            .uri("/_keys/writer")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/ns/ns_1/key_1")
+            .insert_header((header::AUTHORIZATION, bearer))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // unknown tokens are not remembered, so keys created since are accepted right away
+        let token = auth::generate_token();
+        let req = test::TestRequest::get()
+            .uri("/key_1")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        auth::replace(
+            &pool,
+            "reader",
+            &token,
+            &[Grant {
+                namespace: "*".into(),
+                scope: Scope::Read,
+            }],
+        )
+        .await?;
+        let req = test::TestRequest::get()
+            .uri("/key_1")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::error::AppError;
+use crate::namespace::KeyPath;
+use crate::precondition::etag;
+use crate::state::AppState;
+use crate::write_back::{self, Scope};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header::{ETag, HttpDate, LastModified};
+use actix_web::web::Bytes;
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    namespace: String,
+    key: String,
+    /// length of the value in bytes
+    size: i64,
+    content_type: String,
+    version: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    cached: bool,
+}
+
+/// Empty body that keeps a manually set `Content-Length`, so that `HEAD` can report the size of a
+/// value without reading it.
+struct EmptyBody;
+
+impl MessageBody for EmptyBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Poll::Ready(None)
+    }
+}
+
+async fn fetch_metadata(data: &AppState, path: &KeyPath) -> Result<Metadata, AppError> {
+    write_back::flush(data, Scope::Key(&path.cache_key())).await?;
+    let row = sqlx::query!(
+        r#"
+SELECT octet_length(value) AS "size!", content_type, version, created_at, updated_at, expires_at
+FROM kv_store
+WHERE namespace = $1
+  AND key = $2
+  AND (expires_at IS NULL OR expires_at > NOW())
+        "#,
+        path.namespace,
+        path.key
+    )
+    .fetch_one(&data.db_pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::RowNotFound => AppError::NotFound(path.key.clone()),
+        other => AppError::Database(other),
+    })?;
+
+    Ok(Metadata {
+        cached: data.cache.contains(&path.cache_key()),
+        namespace: path.namespace.clone(),
+        key: path.key.clone(),
+        size: row.size.into(),
+        content_type: row.content_type,
+        version: row.version,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        expires_at: row.expires_at,
+    })
+}
+
+/// Responds with the headers `GET /{key}` would send, read from the database without the value.
+#[routes]
+#[head("/{key}")]
+#[head("/ns/{namespace}/{key}")]
+async fn head_kv(path: Path<KeyPath>, data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let meta = fetch_metadata(&data, &path).await?;
+
+    Ok(HttpResponse::Ok()
+        .no_chunking(meta.size as u64)
+        .content_type(meta.content_type)
+        .insert_header(ETag(etag(meta.version)))
+        .insert_header(LastModified(HttpDate::from(SystemTime::from(
+            meta.updated_at,
+        ))))
+        .body(EmptyBody))
+}
+
+#[routes]
+#[get("/_meta/{key}")]
+#[get("/ns/{namespace}/_meta/{key}")]
+async fn get_metadata(
+    path: Path<KeyPath>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(fetch_metadata(&data, &path).await?))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(head_kv);
+    cfg.service(get_metadata);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+    use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_read_metadata(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        sqlx::query!(
+            "INSERT INTO kv_store (key, value, updated_at) VALUES ($1, $2, '2025-11-01T10:00:00Z')",
+            "key_1",
+            "value_1".as_bytes()
+        )
+        .execute(&pool)
+        .await?;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/key_1")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_LENGTH).unwrap(), "7");
+        assert_eq!(
+            res.headers().get(header::LAST_MODIFIED).unwrap(),
+            "Sat, 01 Nov 2025 10:00:00 GMT"
+        );
+        assert!(test::read_body(res).await.is_empty());
+
+        let req = test::TestRequest::get().uri("/_meta/key_1").to_request();
+        let meta: Metadata = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(meta.size, 7);
+        assert!(!meta.cached);
+
+        // populates the cache
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/_meta/key_1").to_request();
+        let meta: Metadata = test::call_and_read_body_json(&app, req).await;
+        assert!(meta.cached);
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/key_2")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+use crate::metrics;
+use crate::state::AppState;
+use actix_web::{get, web, HttpResponse};
+
+/// Exposes the metrics of this instance in the Prometheus text format.
+#[get("/metrics")]
+async fn get_metrics(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&data))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_metrics);
+}
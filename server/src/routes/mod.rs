@@ -1,15 +1,38 @@
 use actix_web::web;
 
+mod batch;
+mod changes;
 mod delete;
 mod flush;
 mod get;
-mod post;
+mod health;
+mod history;
+mod incr;
+mod keys;
+mod meta;
+mod metrics;
+pub(crate) mod post;
+mod put;
+mod scan;
 mod stats;
+mod watch;
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    health::init_routes(cfg);
     stats::init_routes(cfg);
+    keys::init_routes(cfg);
+    metrics::init_routes(cfg);
+    // ahead of the routes of single pairs, so that its pattern is the one reported for it
+    flush::init_routes(cfg);
+    batch::init_routes(cfg);
+    scan::init_routes(cfg);
+    watch::init_routes(cfg);
+    changes::init_routes(cfg);
+    incr::init_routes(cfg);
+    meta::init_routes(cfg);
+    history::init_routes(cfg);
     get::init_routes(cfg);
     post::init_routes(cfg);
+    put::init_routes(cfg);
     delete::init_routes(cfg);
-    flush::init_routes(cfg);
 }
@@ -1,48 +1,111 @@
+use crate::cache::{CacheEntry, CacheKey};
 use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::{validate_key, NamespacePath};
+use crate::precondition::{etag, Precondition};
 use crate::state::AppState;
-use actix_web::{post, web, HttpResponse};
-use actix_web_validator::Json;
+use crate::store::{self, TEXT_CONTENT_TYPE};
+use crate::write_back::{self, Scope};
+use actix_web::http::header::ETag;
+use actix_web::{routes, web, HttpRequest, HttpResponse};
+use actix_web_validator::{Json, Path};
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
-struct Request {
-    #[validate(length(min = 1, max = 512, message = "invalid key length"))]
-    key: String,
+pub(crate) struct Request {
+    #[validate(custom(function = "validate_key"))]
+    pub(crate) key: String,
     #[validate(length(min = 1, max = 4096, message = "invalid value length"))]
-    value: String,
+    pub(crate) value: String,
+    #[validate(range(min = 1, message = "invalid ttl"))]
+    pub(crate) ttl_seconds: Option<i32>,
 }
 
+/// Honors `If-Match` for compare-and-swap updates and `If-None-Match: *` for create-only writes.
+///
+/// With write-back enabled, a write without preconditions is answered with `202 Accepted` before
+/// it reaches the database.
+#[routes]
 #[post("/")]
+#[post("/ns/{namespace}")]
 async fn post_kv(
+    req: HttpRequest,
+    path: Path<NamespacePath>,
     payload: Json<Request>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let row = sqlx::query!(
-        r#"
-INSERT INTO kv_store (key, value)
-VALUES ($1, $2)
-ON CONFLICT (key)
-DO UPDATE
-SET value      = EXCLUDED.value,
-    updated_at = NOW()
-RETURNING (created_at = updated_at) AS inserted
-        "#,
-        payload.key,
-        payload.value
+    let precondition = Precondition::from_request(&req)?;
+    let cache_key = CacheKey::new(path.namespace.clone(), payload.key.clone());
+    if data.write_back.is_enabled() && precondition == Precondition::None {
+        let entry = write_back::write(
+            &data,
+            cache_key,
+            payload.value.clone(),
+            TEXT_CONTENT_TYPE,
+            payload.ttl_seconds,
+        )
+        .await?;
+        return Ok(HttpResponse::Accepted()
+            .insert_header(ETag(etag(entry.version)))
+            .finish());
+    }
+
+    write_back::flush(&data, Scope::Key(&cache_key)).await?;
+    let write = store::Write {
+        namespace: &path.namespace,
+        key: &payload.key,
+        value: payload.value.as_bytes(),
+        content_type: TEXT_CONTENT_TYPE,
+        ttl_seconds: payload.ttl_seconds,
+    };
+    let mut tx = data.db_pool.begin().await?;
+    let row = store::upsert_if(&mut *tx, &write, &precondition)
+        .await?
+        .ok_or_else(|| AppError::PreconditionFailed(payload.key.clone()))?;
+    journal::record_put(
+        &mut tx,
+        data.history,
+        &journal::Put {
+            namespace: write.namespace,
+            key: write.key,
+            version: row.version,
+            value: write.value,
+            content_type: write.content_type,
+            expires_at: row.expires_at,
+        },
+    )
+    .await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::key(
+            &data.instance_id,
+            write.namespace,
+            write.key,
+        )],
     )
-    .fetch_one(&data.db_pool)
     .await?;
+    tx.commit().await?;
 
     data.cache
-        .insert(payload.key.clone(), payload.value.clone())
+        .write(
+            cache_key,
+            CacheEntry::new(
+                payload.value.clone(),
+                TEXT_CONTENT_TYPE,
+                row.version,
+                row.expires_at,
+            ),
+        )
         .await;
 
-    Ok(if row.inserted.unwrap_or(false) {
+    Ok(if row.inserted {
         HttpResponse::Created()
     } else {
         HttpResponse::NoContent()
     }
+    .insert_header(ETag(etag(row.version)))
     .finish())
 }
 
@@ -52,7 +115,10 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
 
 #[cfg(test)]
 mod tests {
-    use crate::test_utils::setup_app::setup_test_app;
+    use crate::cache::Cache;
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::{setup_test_app, setup_test_app_with_state};
+    use actix_web::http::header;
     use actix_web::http::StatusCode;
     use actix_web::test;
     use serde_json::json;
@@ -88,4 +154,154 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn can_insert_key_with_ttl(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_1", "ttl_seconds": 60}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let ttl = sqlx::query_scalar!(
+            r#"SELECT EXTRACT(EPOCH FROM expires_at - NOW())::INT AS "ttl!" FROM kv_store WHERE key = $1"#,
+            "key_1"
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert!((1..=60).contains(&ttl));
+
+        // writing over an expired pair counts as a creation
+        sqlx::query!("UPDATE kv_store SET expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await?;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_2"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "value_2");
+
+        // zero or negative ttl is rejected
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_3", "ttl_seconds": 0}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn can_compare_and_swap(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        // create-only
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::IF_NONE_MATCH, "*"))
+            .set_json(json!({"key": "key_1", "value": "value_1"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::IF_NONE_MATCH, "*"))
+            .set_json(json!({"key": "key_1", "value": "value_2"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::ETAG), Some(&created));
+
+        // update only from the version that was read
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::IF_MATCH, created.clone()))
+            .set_json(json!({"key": "key_1", "value": "value_3"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let updated = res.headers().get(header::ETAG).unwrap().clone();
+        assert_ne!(updated, created);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::IF_MATCH, created.clone()))
+            .set_json(json!({"key": "key_1", "value": "value_4"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::ETAG), Some(&updated));
+        let body = test::read_body(res).await;
+        assert_eq!(str::from_utf8(&body).unwrap(), "value_3");
+
+        // stale deletes are rejected as well
+        let req = test::TestRequest::delete()
+            .uri("/key_1")
+            .insert_header((header::IF_MATCH, created))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::delete()
+            .uri("/key_1")
+            .insert_header((header::IF_MATCH, updated))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::IF_MATCH, "*"))
+            .set_json(json!({"key": "key_1", "value": "value_5"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_forgets_missing_key(pool: PgPool) -> sqlx::Result<()> {
+        let cache = Cache::new(64).with_negative_cache(64, std::time::Duration::from_secs(60));
+        let state = AppState::new(pool.clone(), cache).await;
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/key_1").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(state.cache.stats().negative_hits, 1);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_1"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&res).unwrap(), "value_1");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,211 @@
+use crate::cache::CacheEntry;
+use crate::error::AppError;
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::namespace::KeyPath;
+use crate::precondition::{etag, Precondition};
+use crate::state::AppState;
+use crate::store;
+use crate::write_back::{self, Scope};
+use actix_web::http::header::{self, ETag};
+use actix_web::{guard, web, HttpRequest, HttpResponse};
+use actix_web_validator::{Path, Query};
+use serde::Deserialize;
+use validator::Validate;
+
+/// Content type stored when a request does not specify one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+/// Largest raw value accepted, in bytes.
+const MAX_VALUE_BYTES: usize = 1024 * 1024;
+/// Longest `Content-Type` stored, in bytes.
+const MAX_CONTENT_TYPE_BYTES: usize = 255;
+
+#[derive(Deserialize, Validate)]
+struct Params {
+    #[validate(range(min = 1, message = "invalid ttl"))]
+    ttl_seconds: Option<i32>,
+}
+
+/// Stores the raw request body as the value, along with its `Content-Type`. Honors the same
+/// preconditions as `POST /`, and is written back the same way.
+async fn put_kv(
+    req: HttpRequest,
+    path: Path<KeyPath>,
+    params: Query<Params>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if body.is_empty() {
+        return Err(AppError::BadRequest("invalid value length".into()));
+    }
+
+    let content_type = match req.headers().get(header::CONTENT_TYPE) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| AppError::BadRequest("invalid Content-Type header".into()))?,
+        None => DEFAULT_CONTENT_TYPE,
+    };
+    if content_type.len() > MAX_CONTENT_TYPE_BYTES {
+        return Err(AppError::BadRequest("Content-Type header too long".into()));
+    }
+
+    let precondition = Precondition::from_request(&req)?;
+    if data.write_back.is_enabled() && precondition == Precondition::None {
+        let entry = write_back::write(
+            &data,
+            path.cache_key(),
+            body,
+            content_type,
+            params.ttl_seconds,
+        )
+        .await?;
+        return Ok(HttpResponse::Accepted()
+            .insert_header(ETag(etag(entry.version)))
+            .finish());
+    }
+
+    write_back::flush(&data, Scope::Key(&path.cache_key())).await?;
+    let write = store::Write {
+        namespace: &path.namespace,
+        key: &path.key,
+        value: &body,
+        content_type,
+        ttl_seconds: params.ttl_seconds,
+    };
+    let mut tx = data.db_pool.begin().await?;
+    let row = store::upsert_if(&mut *tx, &write, &precondition)
+        .await?
+        .ok_or_else(|| AppError::PreconditionFailed(path.key.clone()))?;
+    journal::record_put(
+        &mut tx,
+        data.history,
+        &journal::Put {
+            namespace: write.namespace,
+            key: write.key,
+            version: row.version,
+            value: write.value,
+            content_type: write.content_type,
+            expires_at: row.expires_at,
+        },
+    )
+    .await?;
+    invalidation::publish(
+        &mut *tx,
+        &[Invalidation::key(
+            &data.instance_id,
+            write.namespace,
+            write.key,
+        )],
+    )
+    .await?;
+    tx.commit().await?;
+
+    data.cache
+        .write(
+            path.cache_key(),
+            CacheEntry::new(body, content_type, row.version, row.expires_at),
+        )
+        .await;
+
+    Ok(if row.inserted {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::NoContent()
+    }
+    .insert_header(ETag(etag(row.version)))
+    .finish())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    // registered by hand rather than with `#[put]`, so that the larger body limit is its own
+    cfg.service(
+        web::resource(["/{key}", "/ns/{namespace}/{key}"])
+            .name("put_kv")
+            .guard(guard::Put())
+            .app_data(web::PayloadConfig::new(MAX_VALUE_BYTES))
+            .to(put_kv),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_put_binary_value(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+        let value: Vec<u8> = (0..=255).collect();
+
+        let req = test::TestRequest::put()
+            .uri("/key_1?ttl_seconds=60")
+            .insert_header((header::CONTENT_TYPE, "image/png"))
+            .set_payload(value.clone())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        assert_eq!(test::read_body(res).await, value);
+
+        // defaults to an opaque content type
+        let req = test::TestRequest::put()
+            .uri("/key_1")
+            .set_payload(vec![0xff, 0x00])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/octet-stream"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/_batch/get")
+            .set_json(json!({"keys": ["key_1"]}))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["found"]["key_1"], json!({"base64": "/wA="}));
+
+        let req = test::TestRequest::put().uri("/key_2").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri("/key_2")
+            .insert_header((header::CONTENT_TYPE, format!("text/{}", "x".repeat(256))))
+            .set_payload("value_2")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // values up to 1 MiB are accepted, past the default body limit
+        let req = test::TestRequest::put()
+            .uri("/key_2")
+            .set_payload(vec![0; 1024 * 1024])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::put()
+            .uri("/key_2")
+            .set_payload(vec![0; 1024 * 1024 + 1])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+}
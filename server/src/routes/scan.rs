@@ -0,0 +1,219 @@
+use crate::error::AppError;
+use crate::namespace::{validate_key, NamespacePath};
+use crate::state::AppState;
+use crate::value::JsonValue;
+use crate::write_back::{self, Scope};
+use actix_web::{routes, web, HttpResponse};
+use actix_web_validator::{Path, Query};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize, Validate)]
+struct Params {
+    #[validate(custom(function = "validate_key"))]
+    prefix: Option<String>,
+    /// inclusive lower bound
+    #[validate(custom(function = "validate_key"))]
+    start: Option<String>,
+    /// exclusive upper bound
+    #[validate(custom(function = "validate_key"))]
+    end: Option<String>,
+    #[validate(range(min = 1, max = 1000, message = "invalid limit"))]
+    limit: Option<i64>,
+    cursor: Option<String>,
+    #[serde(default)]
+    values: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Item {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScanResponse {
+    items: Vec<Item>,
+    /// passed back as `cursor` to fetch the next page, absent on the last page
+    cursor: Option<String>,
+}
+
+fn encode_cursor(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .filter(|key| validate_key(key).is_ok())
+        .ok_or_else(|| AppError::BadRequest("invalid cursor".into()))
+}
+
+/// The smallest key greater than every key starting with `prefix`, keys comparing byte-wise, or
+/// `None` if there is none.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // surrogates are skipped, as they are not chars
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Lists pairs in key order straight from the database, bypassing the cache.
+///
+/// Pages are keyed on the last key returned rather than an offset, so a pair that exists for the
+/// whole scan is returned exactly once even while other pairs are written or deleted.
+#[routes]
+#[get("/_scan")]
+#[get("/ns/{namespace}/_scan")]
+async fn scan_kv(
+    path: Path<NamespacePath>,
+    params: Query<Params>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let after = params.cursor.as_deref().map(decode_cursor).transpose()?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    // a prefix is turned into a range, so that only the keys scanned are read from the index
+    let from = params.start.clone().max(params.prefix.clone());
+    let to = [
+        params.prefix.as_deref().and_then(prefix_end),
+        params.end.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    write_back::flush(&data, Scope::Namespace(&path.namespace)).await?;
+
+    let mut rows = sqlx::query!(
+        r#"
+SELECT key, CASE WHEN $5 THEN value END AS value
+FROM kv_store
+WHERE namespace = $1
+  AND ($2::TEXT IS NULL OR key >= $2)
+  AND ($3::TEXT IS NULL OR key < $3)
+  AND ($4::TEXT IS NULL OR key > $4)
+  AND (expires_at IS NULL OR expires_at > NOW())
+ORDER BY namespace, key
+LIMIT $6
+        "#,
+        path.namespace,
+        from,
+        to,
+        after,
+        params.values,
+        limit + 1
+    )
+    .fetch_all(&data.db_pool)
+    .await?;
+
+    // the extra row only tells whether another page follows
+    let cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| encode_cursor(&row.key))
+    } else {
+        None
+    };
+
+    let items = rows
+        .into_iter()
+        .map(|row| Item {
+            key: row.key,
+            value: row.value.as_deref().map(JsonValue::from),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ScanResponse { items, cursor }))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(scan_kv);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScanResponse;
+    use crate::test_utils::setup_app::setup_test_app;
+    use crate::value::JsonValue;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn can_scan_keys(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        sqlx::query!(
+            r#"
+INSERT INTO kv_store (key, value, expires_at)
+VALUES ('a_1', 'value_1', NULL),
+       ('a_2', 'value_2', NULL),
+       ('a_3', 'value_3', NOW() - INTERVAL '1 second'),
+       ('a_4', 'value_4', NULL),
+       ('a_5', 'value_5', NULL),
+       ('a`', 'value_6', NULL),
+       ('b_1', 'value_7', NULL)
+            "#
+        )
+        .execute(&pool)
+        .await?;
+
+        let req = test::TestRequest::get()
+            .uri("/_scan?prefix=a_&limit=2&values=true")
+            .to_request();
+        let page: ScanResponse = test::call_and_read_body_json(&app, req).await;
+        let keys: Vec<_> = page.items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["a_1", "a_2"]);
+        assert_eq!(page.items[0].value, Some(JsonValue::Text("value_1".into())));
+
+        // a pair written behind the cursor does not shift the next page
+        sqlx::query!("INSERT INTO kv_store (key, value) VALUES ('a_0', 'value_0')")
+            .execute(&pool)
+            .await?;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/_scan?prefix=a_&limit=2&cursor={}",
+                page.cursor.unwrap()
+            ))
+            .to_request();
+        let page: ScanResponse = test::call_and_read_body_json(&app, req).await;
+        let keys: Vec<_> = page.items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["a_4", "a_5"]);
+        assert_eq!(page.items[0].value, None);
+        assert!(page.cursor.is_none());
+
+        let req = test::TestRequest::get()
+            .uri("/_scan?start=a_2&end=b_1")
+            .to_request();
+        let page: ScanResponse = test::call_and_read_body_json(&app, req).await;
+        let keys: Vec<_> = page.items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["a_2", "a_4", "a_5", "a`"]);
+
+        // bounds are validated like keys
+        for params in ["prefix=a%00", "start=a%00", "end=", "cursor=YQA"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/_scan?{}", params))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", params);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/_scan?cursor=!!")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+}
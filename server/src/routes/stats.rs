@@ -1,10 +1,28 @@
+use crate::cache::CacheStats;
 use crate::error::AppError;
+use crate::rate_limit::RateLimitStats;
 use crate::state::AppState;
+use crate::write_back::WriteBackStats;
 use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Stats {
+    #[serde(flatten)]
+    cache: CacheStats,
+    #[serde(flatten)]
+    write_back: WriteBackStats,
+    #[serde(flatten)]
+    rate_limit: RateLimitStats,
+}
 
 #[get("/stats")]
 async fn get_stats(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
-    Ok(HttpResponse::Ok().json(data.cache.stats()))
+    Ok(HttpResponse::Ok().json(Stats {
+        cache: data.cache.stats(),
+        write_back: data.write_back.stats(),
+        rate_limit: data.rate_limit.stats(),
+    }))
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
@@ -0,0 +1,277 @@
+use crate::changes::{self, Batch, Change, Filter, Op, PAGE_SIZE};
+use crate::error::AppError;
+use crate::namespace::NamespacePath;
+use crate::state::AppState;
+use crate::value::JsonValue;
+use actix_rt::time::{interval_at, Instant, Interval};
+use actix_web::http::header::{self, CacheControl, CacheDirective};
+use actix_web::web::Bytes;
+use actix_web::{routes, web, HttpRequest, HttpResponse};
+use actix_web_validator::{Path, Query};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use validator::Validate;
+
+/// Idle time after which a comment is sent, so that proxies keep the stream open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, Validate)]
+struct Params {
+    #[validate(length(min = 1, max = 512, message = "invalid key length"))]
+    key: Option<String>,
+    #[validate(length(max = 512, message = "invalid prefix length"))]
+    prefix: Option<String>,
+    /// resumes after this sequence number, taken from `Last-Event-ID` if absent
+    since: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Event {
+    namespace: String,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+/// Encodes a change as a server-sent event, identified by its sequence number.
+fn encode(change: Change) -> Result<Bytes, AppError> {
+    let op = match change.op {
+        Op::Put => "put",
+        Op::Delete => "delete",
+    };
+    let event = Event {
+        namespace: change.namespace,
+        key: change.key,
+        version: change.version,
+        value: change.value.as_deref().map(JsonValue::from),
+        content_type: change.content_type,
+    };
+
+    Ok(Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        change.seq,
+        op,
+        serde_json::to_string(&event)?
+    )))
+}
+
+/// State of a single watcher. Every change matching `filter` up to `cursor` has been sent or is
+/// `pending`.
+struct Watch {
+    db_pool: PgPool,
+    filter: Filter,
+    receiver: broadcast::Receiver<Arc<Batch>>,
+    pending: VecDeque<Change>,
+    cursor: i64,
+    /// Set while changes up to this sequence number have to be read from the database, because
+    /// they were never received from the feed.
+    catch_up_to: Option<i64>,
+    keep_alive: Interval,
+}
+
+impl Watch {
+    /// Waits for the next chunk of the stream, `None` once the feed is gone.
+    async fn next(&mut self) -> Option<Result<Bytes, AppError>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                self.keep_alive.reset();
+                return Some(encode(change));
+            }
+
+            if let Some(target) = self.catch_up_to {
+                let page = match changes::list(
+                    &self.db_pool,
+                    &self.filter,
+                    self.cursor,
+                    Some(target),
+                    PAGE_SIZE,
+                )
+                .await
+                {
+                    Ok(page) => page,
+                    Err(err) => return Some(Err(err.into())),
+                };
+
+                match page.last() {
+                    Some(last) if page.len() as i64 == PAGE_SIZE => self.cursor = last.seq,
+                    _ => {
+                        self.cursor = target;
+                        self.catch_up_to = None;
+                    }
+                }
+                self.pending.extend(page);
+                continue;
+            }
+
+            tokio::select! {
+                batch = self.receiver.recv() => match batch {
+                    Ok(batch) if batch.up_to <= self.cursor => {}
+                    // changes were published before this watcher caught up with the log
+                    Ok(batch) if batch.after > self.cursor => self.catch_up_to = Some(batch.up_to),
+                    Ok(batch) => {
+                        self.pending.extend(
+                            batch
+                                .changes
+                                .iter()
+                                .filter(|change| {
+                                    change.seq > self.cursor && self.filter.matches(change)
+                                })
+                                .cloned(),
+                        );
+                        self.cursor = batch.up_to;
+                    }
+                    Err(RecvError::Lagged(_)) => match changes::latest_seq(&self.db_pool).await {
+                        Ok(latest) => self.catch_up_to = Some(latest),
+                        Err(err) => return Some(Err(err.into())),
+                    },
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.keep_alive.tick() => return Some(Ok(Bytes::from_static(b": keep-alive\n\n"))),
+            }
+        }
+    }
+}
+
+/// Streams changes to the pairs of a namespace as server-sent events, optionally narrowed to a
+/// single `key` or a key `prefix`.
+///
+/// Each event carries its sequence number as its id, so that a client reconnecting with
+/// `Last-Event-ID` (or `since`) receives every change it missed, as long as it has not been
+/// compacted away.
+#[routes]
+#[get("/_watch")]
+#[get("/ns/{namespace}/_watch")]
+async fn watch_kv(
+    req: HttpRequest,
+    path: Path<NamespacePath>,
+    params: Query<Params>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let params = params.into_inner();
+    if params.key.is_some() && params.prefix.is_some() {
+        return Err(AppError::BadRequest(
+            "key and prefix are mutually exclusive".into(),
+        ));
+    }
+
+    let since = match (params.since, req.headers().get("Last-Event-ID")) {
+        (Some(since), _) => Some(since),
+        (None, Some(value)) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| AppError::BadRequest("invalid Last-Event-ID header".into()))?,
+        ),
+        (None, None) => None,
+    };
+
+    if let Some(since) = since {
+        let compacted_through = changes::compacted_through(&data.db_pool).await?;
+        if since < compacted_through {
+            return Err(AppError::Gone(format!(
+                "changes up to {} have been compacted",
+                compacted_through
+            )));
+        }
+    }
+
+    // subscribed before reading the log, so that nothing committed in between is missed
+    let receiver = data.changes.subscribe();
+    let latest = changes::latest_seq(&data.db_pool).await?;
+    let (cursor, catch_up_to) = match since {
+        Some(since) => (since, (since < latest).then_some(latest)),
+        None => (latest, None),
+    };
+
+    let watch = Watch {
+        db_pool: data.db_pool.clone(),
+        filter: Filter {
+            namespace: Some(path.into_inner().namespace),
+            key: params.key,
+            prefix: params.prefix,
+        },
+        receiver,
+        pending: VecDeque::new(),
+        cursor,
+        catch_up_to,
+        keep_alive: interval_at(Instant::now() + KEEP_ALIVE, KEEP_ALIVE),
+    };
+    let events = stream::unfold(watch, |mut watch| async move {
+        watch.next().await.map(|chunk| (chunk, watch))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // keeps reverse proxies from buffering the stream
+        .insert_header((header::HeaderName::from_static("x-accel-buffering"), "no"))
+        .streaming(events))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(watch_kv);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::setup_app::setup_test_app;
+    use actix_web::body::MessageBody;
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::future::poll_fn;
+    use std::pin::pin;
+
+    #[sqlx::test]
+    async fn can_watch_prefix_from_sequence(pool: PgPool) -> sqlx::Result<()> {
+        let app = setup_test_app(pool.clone()).await;
+
+        for (key, value) in [("key_1", "value_1"), ("other_1", "value_1")] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": key, "value": value}))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::delete().uri("/key_1").to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_watch?prefix=key_&since=0")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        let mut body = pin!(res.into_body());
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let chunk = poll_fn(|cx| body.as_mut().poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            events.push(String::from_utf8(chunk.to_vec()).unwrap());
+        }
+
+        assert!(events[0].starts_with("id: "));
+        assert!(events[0].contains("event: put\n"));
+        assert!(events[0].contains(r#""key":"key_1","version""#));
+        assert!(events[0].contains(r#""value":"value_1""#));
+        assert!(events[1].contains("event: delete\n"));
+        assert!(events[1].contains(r#"data: {"namespace":"default","key":"key_1"}"#));
+
+        Ok(())
+    }
+}
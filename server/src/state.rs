@@ -1,17 +1,75 @@
+use crate::auth::Auth;
 use crate::cache::Cache;
+use crate::changes::ChangeFeed;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::write_back::WriteBack;
 use sqlx::PgPool;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub db_pool: PgPool,
     pub cache: Cache,
+    pub changes: ChangeFeed,
+    /// Whether writes and deletes are recorded in `kv_history`.
+    pub history: bool,
+    /// Identifies this instance in the invalidations it publishes.
+    pub instance_id: String,
+    /// Buffers writes when write-back is enabled.
+    pub write_back: WriteBack,
+    pub metrics: Metrics,
+    pub health: Health,
+    /// Authenticates requests when enabled.
+    pub auth: Auth,
+    /// Limits the requests of each client when enabled.
+    pub rate_limit: RateLimiter,
 }
 
 impl AppState {
-    pub async fn new(db_pool: PgPool, cache_capacity: u64) -> Self {
+    pub async fn new(db_pool: PgPool, cache: Cache) -> Self {
         Self {
             db_pool,
-            cache: Cache::new(cache_capacity),
+            cache,
+            changes: ChangeFeed::new(),
+            history: false,
+            instance_id: Uuid::new_v4().to_string(),
+            write_back: WriteBack::default(),
+            metrics: Metrics::new(),
+            health: Health::default(),
+            auth: Auth::default(),
+            rate_limit: RateLimiter::default(),
         }
     }
+
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn with_write_back(mut self, write_back: WriteBack) -> Self {
+        self.write_back = write_back;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimiter) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
 }
@@ -0,0 +1,313 @@
+use crate::journal;
+use crate::precondition::Precondition;
+use chrono::{DateTime, Utc};
+use sqlx::PgExecutor;
+
+/// Content type of values written through the JSON API.
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// A pair to be written to the database.
+#[derive(Debug, Clone)]
+pub struct Write<'a> {
+    pub namespace: &'a str,
+    pub key: &'a str,
+    pub value: &'a [u8],
+    pub content_type: &'a str,
+    /// Replaces any previous expiry; `None` makes the pair persistent.
+    pub ttl_seconds: Option<i32>,
+}
+
+/// Outcome of writing a pair to the database.
+#[derive(Debug, Clone)]
+pub struct Upserted {
+    /// Whether the pair did not exist (or had expired) before the write.
+    pub inserted: bool,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of incrementing an integer pair.
+#[derive(Debug, Clone)]
+pub struct Incremented {
+    /// The committed value, as stored.
+    pub value: Vec<u8>,
+    pub version: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Inserts or overwrites a pair.
+pub async fn upsert(executor: impl PgExecutor<'_>, write: &Write<'_>) -> sqlx::Result<Upserted> {
+    let row = sqlx::query!(
+        r#"
+INSERT INTO kv_store (namespace, key, value, content_type, expires_at)
+VALUES ($1, $2, $3, $4, NOW() + $5::INT * INTERVAL '1 second')
+ON CONFLICT (namespace, key)
+DO UPDATE
+SET value        = EXCLUDED.value,
+    content_type = EXCLUDED.content_type,
+    expires_at   = EXCLUDED.expires_at,
+    version      = nextval('kv_store_version_seq'),
+    created_at   = CASE
+                       WHEN kv_store.expires_at <= NOW() THEN NOW()
+                       ELSE kv_store.created_at
+                   END,
+    updated_at   = NOW()
+RETURNING (created_at = updated_at) AS inserted, version, expires_at
+        "#,
+        write.namespace,
+        write.key,
+        write.value,
+        write.content_type,
+        write.ttl_seconds
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(Upserted {
+        inserted: row.inserted.unwrap_or(false),
+        version: row.version,
+        expires_at: row.expires_at,
+    })
+}
+
+/// Inserts or overwrites many pairs at versions drawn beforehand, in a single statement. Pairs
+/// stored at a later version meanwhile are left as they are, so that versions only increase.
+/// Returns the namespaces and keys of the pairs written.
+pub async fn upsert_versioned(
+    executor: impl PgExecutor<'_>,
+    puts: &[journal::Put<'_>],
+) -> sqlx::Result<Vec<(String, String)>> {
+    let mut namespaces = Vec::with_capacity(puts.len());
+    let mut keys = Vec::with_capacity(puts.len());
+    let mut values = Vec::with_capacity(puts.len());
+    let mut content_types = Vec::with_capacity(puts.len());
+    let mut expiries = Vec::with_capacity(puts.len());
+    let mut versions = Vec::with_capacity(puts.len());
+    for put in puts {
+        namespaces.push(put.namespace);
+        keys.push(put.key);
+        values.push(put.value);
+        content_types.push(put.content_type);
+        expiries.push(put.expires_at);
+        versions.push(put.version);
+    }
+
+    let rows = sqlx::query!(
+        r#"
+INSERT INTO kv_store (namespace, key, value, content_type, expires_at, version)
+SELECT *
+FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BYTEA[], $4::TEXT[], $5::TIMESTAMPTZ[], $6::BIGINT[])
+ON CONFLICT (namespace, key)
+DO UPDATE
+SET value        = EXCLUDED.value,
+    content_type = EXCLUDED.content_type,
+    expires_at   = EXCLUDED.expires_at,
+    version      = EXCLUDED.version,
+    created_at   = CASE
+                       WHEN kv_store.expires_at <= NOW() THEN NOW()
+                       ELSE kv_store.created_at
+                   END,
+    updated_at   = NOW()
+WHERE kv_store.version < EXCLUDED.version
+RETURNING namespace, key
+        "#,
+        &namespaces as &[&str],
+        &keys as &[&str],
+        &values as &[&[u8]],
+        &content_types as &[&str],
+        &expiries as &[Option<DateTime<Utc>>],
+        &versions
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.namespace, row.key))
+        .collect())
+}
+
+/// Like [`upsert`], but only writes if `precondition` holds, returning `None` otherwise.
+pub async fn upsert_if(
+    executor: impl PgExecutor<'_>,
+    write: &Write<'_>,
+    precondition: &Precondition,
+) -> sqlx::Result<Option<Upserted>> {
+    let versions = match precondition {
+        Precondition::None => return upsert(executor, write).await.map(Some),
+        Precondition::Absent => {
+            // an expired pair is as good as absent, so it may be overwritten
+            let row = sqlx::query!(
+                r#"
+INSERT INTO kv_store (namespace, key, value, content_type, expires_at)
+VALUES ($1, $2, $3, $4, NOW() + $5::INT * INTERVAL '1 second')
+ON CONFLICT (namespace, key)
+DO UPDATE
+SET value        = EXCLUDED.value,
+    content_type = EXCLUDED.content_type,
+    expires_at   = EXCLUDED.expires_at,
+    version      = nextval('kv_store_version_seq'),
+    created_at   = NOW(),
+    updated_at   = NOW()
+WHERE kv_store.expires_at <= NOW()
+RETURNING version, expires_at
+                "#,
+                write.namespace,
+                write.key,
+                write.value,
+                write.content_type,
+                write.ttl_seconds
+            )
+            .fetch_optional(executor)
+            .await?;
+
+            return Ok(row.map(|row| Upserted {
+                inserted: true,
+                version: row.version,
+                expires_at: row.expires_at,
+            }));
+        }
+        Precondition::Exists => None,
+        Precondition::Matches(versions) => Some(versions.as_slice()),
+    };
+
+    let row = sqlx::query!(
+        r#"
+UPDATE kv_store
+SET value        = $3,
+    content_type = $4,
+    expires_at   = NOW() + $5::INT * INTERVAL '1 second',
+    version      = nextval('kv_store_version_seq'),
+    updated_at   = NOW()
+WHERE namespace = $1
+  AND key = $2
+  AND (expires_at IS NULL OR expires_at > NOW())
+  AND ($6::BIGINT[] IS NULL OR version = ANY($6))
+RETURNING version, expires_at
+        "#,
+        write.namespace,
+        write.key,
+        write.value,
+        write.content_type,
+        write.ttl_seconds,
+        versions
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.map(|row| Upserted {
+        inserted: false,
+        version: row.version,
+        expires_at: row.expires_at,
+    }))
+}
+
+/// Atomically adds `delta` to an integer pair, creating it with `delta` as its value if it does not
+/// exist (or has expired). The expiry of a live pair is kept.
+///
+/// Fails with an `invalid_text_representation` or `character_not_in_repertoire` error if the
+/// current value is not an integer and with `numeric_value_out_of_range` if the result would
+/// overflow.
+pub async fn increment(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    delta: i64,
+) -> sqlx::Result<Incremented> {
+    let row = sqlx::query!(
+        r#"
+INSERT INTO kv_store (namespace, key, value, content_type)
+VALUES ($1, $2, convert_to($3::BIGINT::TEXT, 'UTF8'), $4)
+ON CONFLICT (namespace, key)
+DO UPDATE
+SET value        = CASE
+                       WHEN kv_store.expires_at <= NOW() THEN EXCLUDED.value
+                       ELSE convert_to(
+                           (convert_from(kv_store.value, 'UTF8')::BIGINT + $3)::TEXT, 'UTF8'
+                       )
+                   END,
+    content_type = EXCLUDED.content_type,
+    expires_at   = CASE
+                       WHEN kv_store.expires_at <= NOW() THEN NULL
+                       ELSE kv_store.expires_at
+                   END,
+    version      = nextval('kv_store_version_seq'),
+    created_at   = CASE
+                       WHEN kv_store.expires_at <= NOW() THEN NOW()
+                       ELSE kv_store.created_at
+                   END,
+    updated_at   = NOW()
+RETURNING value, version, expires_at
+        "#,
+        namespace,
+        key,
+        delta,
+        TEXT_CONTENT_TYPE
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(Incremented {
+        value: row.value,
+        version: row.version,
+        expires_at: row.expires_at,
+    })
+}
+
+/// Deletes a pair, returning `false` if it did not exist. Expired pairs are removed as well, but
+/// reported as missing.
+pub async fn delete(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+) -> sqlx::Result<bool> {
+    let live = sqlx::query_scalar!(
+        r#"
+DELETE FROM kv_store
+WHERE namespace = $1
+  AND key = $2
+RETURNING (expires_at IS NULL OR expires_at > NOW()) AS "live!"
+        "#,
+        namespace,
+        key
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(live.unwrap_or(false))
+}
+
+/// Deletes every pair of `namespace`, returning how many were removed.
+pub async fn flush(executor: impl PgExecutor<'_>, namespace: &str) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM kv_store WHERE namespace = $1", namespace)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Deletes a live pair only if it is currently at one of `versions`, returning `false` if nothing
+/// was deleted.
+pub async fn delete_if_matches(
+    executor: impl PgExecutor<'_>,
+    namespace: &str,
+    key: &str,
+    versions: &[i64],
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        r#"
+DELETE FROM kv_store
+WHERE namespace = $1
+  AND key = $2
+  AND (expires_at IS NULL OR expires_at > NOW())
+  AND version = ANY($3)
+        "#,
+        namespace,
+        key,
+        versions
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
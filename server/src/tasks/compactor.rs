@@ -0,0 +1,29 @@
+use crate::changes;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Trims the change log up to the lowest consumer checkpoint, or to `retention` while there is no
+/// consumer, every `interval`, in batches of `batch_size` sequence numbers.
+pub async fn run(db_pool: PgPool, interval: Duration, batch_size: i64, retention: Duration) {
+    let mut ticker = actix_rt::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let mut compacted = 0;
+        loop {
+            match changes::compact(&db_pool, batch_size, retention).await {
+                Ok(Some(count)) => compacted += count,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!("failed to compact the change log: {:?}", err);
+                    break;
+                }
+            }
+        }
+
+        if compacted > 0 {
+            tracing::info!("compacted {} changes", compacted);
+        }
+    }
+}
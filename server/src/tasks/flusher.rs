@@ -0,0 +1,20 @@
+use crate::state::AppState;
+use crate::write_back::{self, Scope};
+use std::time::Duration;
+
+/// Writes buffered writes back to the database every `interval`, or as soon as a full batch is
+/// waiting.
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticker = actix_rt::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = state.write_back.full() => {}
+        }
+
+        if let Err(err) = write_back::flush(&state, Scope::All).await {
+            tracing::warn!("failed to write back dirty pairs: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::cache::{Cache, CacheKey};
+use crate::invalidation::{Invalidation, CHANNEL};
+use crate::state::AppState;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::time::Duration;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Time between polls of the change log at least. Each locks the log exclusively, holding up writes
+/// on every instance.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Opens a dedicated connection listening for invalidations, retrying with exponential backoff
+/// until it succeeds.
+async fn listen(db_pool: &PgPool) -> PgListener {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let listener = async {
+            let mut listener = PgListener::connect_with(db_pool).await?;
+            listener.listen(CHANNEL).await?;
+            Ok::<_, sqlx::Error>(listener)
+        }
+        .await;
+
+        match listener {
+            Ok(listener) => return listener,
+            Err(err) => {
+                tracing::warn!(
+                    "failed to listen for invalidations, retrying in {:?}: {:?}",
+                    backoff,
+                    err
+                );
+                actix_rt::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Drops the pairs named by an invalidation from `cache`, unless this instance published it.
+async fn apply(cache: &Cache, instance_id: &str, payload: &str) {
+    let invalidation: Invalidation = match serde_json::from_str(payload) {
+        Ok(invalidation) => invalidation,
+        Err(err) => {
+            tracing::warn!("ignoring malformed invalidation {:?}: {:?}", payload, err);
+            return;
+        }
+    };
+
+    if invalidation.origin == instance_id {
+        return;
+    }
+
+    match invalidation.key {
+        Some(key) => {
+            cache
+                .remove(&CacheKey::new(invalidation.namespace, key))
+                .await
+        }
+        None => cache.flush_namespace(&invalidation.namespace),
+    }
+}
+
+/// Publishes new changes to the watchers of this instance whenever requested, one poll at a time:
+/// notifications received during a poll are all served by the next one.
+async fn poll_changes(state: &AppState) {
+    loop {
+        state.changes.poll_requested().await;
+        if let Err(err) = state.changes.poll(&state.db_pool).await {
+            // picked up by the next poll
+            tracing::warn!("failed to read the change log: {:?}", err);
+        }
+        actix_rt::time::sleep(MIN_POLL_INTERVAL).await;
+    }
+}
+
+async fn receive(state: &AppState) {
+    loop {
+        let mut listener = listen(&state.db_pool).await;
+        state.cache.flush();
+        state.changes.request_poll();
+
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => {
+                    apply(&state.cache, &state.instance_id, notification.payload()).await;
+                    state.changes.request_poll();
+                }
+                Ok(None) => {
+                    tracing::warn!("lost invalidation listener connection, reconnecting");
+                    break;
+                }
+                Err(err) => {
+                    tracing::warn!("invalidation listener failed, reconnecting: {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the cache consistent with changes made by other instances sharing the database, and
+/// feeds changes made by any instance to the watchers of this one.
+///
+/// Notifications sent while the listener is disconnected are lost, so the whole cache is flushed
+/// every time it (re)connects. Watchers miss nothing, as the change log is read up from where it
+/// was left.
+pub async fn run(state: AppState) {
+    tokio::join!(receive(&state), poll_changes(&state));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, listen};
+    use crate::cache::{Cache, CacheEntry, CacheKey};
+    use crate::invalidation::{publish, Invalidation};
+    use crate::store::TEXT_CONTENT_TYPE;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn invalidates_pairs_changed_elsewhere(pool: PgPool) -> sqlx::Result<()> {
+        let cache = Cache::new(8);
+        let mut listener = listen(&pool).await;
+
+        let entry = CacheEntry::new("value_1", TEXT_CONTENT_TYPE, 1, None);
+        for key in ["key_1", "key_2"] {
+            cache.insert(CacheKey::new("ns", key), entry.clone()).await;
+        }
+
+        publish(
+            &pool,
+            &[
+                Invalidation::key("instance_1", "ns", "key_1"),
+                Invalidation::key("instance_2", "ns", "key_2"),
+            ],
+        )
+        .await?;
+        for _ in 0..2 {
+            let notification = listener.recv().await?;
+            apply(&cache, "instance_1", notification.payload()).await;
+        }
+
+        // own invalidations are skipped, as the cache is updated along with the write
+        assert!(cache.contains(&CacheKey::new("ns", "key_1")));
+        assert!(!cache.contains(&CacheKey::new("ns", "key_2")));
+
+        Ok(())
+    }
+}
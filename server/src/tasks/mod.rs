@@ -0,0 +1,5 @@
+pub mod compactor;
+pub mod flusher;
+pub mod listener;
+pub mod pruner;
+pub mod reaper;
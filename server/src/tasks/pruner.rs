@@ -0,0 +1,18 @@
+use crate::history::{self, Retention};
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Drops versions outside of `retention` from the history every `interval`.
+pub async fn run(db_pool: PgPool, interval: Duration, retention: Retention) {
+    let mut ticker = actix_rt::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        match history::prune(&db_pool, &retention).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("pruned {} versions from history", count),
+            Err(err) => tracing::warn!("failed to prune history: {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Deletes at most `batch_size` expired pairs and returns how many were removed.
+pub async fn reap_expired(db_pool: &PgPool, batch_size: i64) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+DELETE FROM kv_store
+WHERE (namespace, key) IN (SELECT namespace, key
+                           FROM kv_store
+                           WHERE expires_at <= NOW()
+                           LIMIT $1 FOR UPDATE SKIP LOCKED)
+        "#,
+        batch_size
+    )
+    .execute(db_pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Removes expired pairs from the database every `interval`, in batches of `batch_size` so that a
+/// large backlog never holds locks on the whole table at once.
+pub async fn run(db_pool: PgPool, interval: Duration, batch_size: i64) {
+    let mut ticker = actix_rt::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let mut reaped = 0;
+        loop {
+            match reap_expired(&db_pool, batch_size).await {
+                Ok(count) => {
+                    reaped += count;
+                    if count < batch_size as u64 {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("failed to reap expired pairs: {:?}", err);
+                    break;
+                }
+            }
+        }
+
+        if reaped > 0 {
+            tracing::info!("reaped {} expired pairs", reaped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reap_expired;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn reaps_only_expired_pairs(pool: PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO kv_store (key, value, expires_at)
+VALUES ('key_1', 'value_1', NOW() - INTERVAL '1 second'),
+       ('key_2', 'value_2', NOW() - INTERVAL '1 second'),
+       ('key_3', 'value_3', NOW() + INTERVAL '1 hour'),
+       ('key_4', 'value_4', NULL)
+            "#
+        )
+        .execute(&pool)
+        .await?;
+
+        assert_eq!(reap_expired(&pool, 1).await?, 1);
+        assert_eq!(reap_expired(&pool, 10).await?, 1);
+        assert_eq!(reap_expired(&pool, 10).await?, 0);
+
+        let keys = sqlx::query_scalar!("SELECT key FROM kv_store ORDER BY key")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(keys, vec!["key_3", "key_4"]);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+use crate::auth;
+use crate::cache::Cache;
+use crate::metrics;
+use crate::rate_limit;
 use crate::routes;
 use crate::state::AppState;
 use sqlx::PgPool;
@@ -9,10 +13,22 @@ pub async fn setup_test_app(
     Response = actix_web::dev::ServiceResponse,
     Error = actix_web::Error,
 > {
-    let state = AppState::new(pool, 64).await;
+    setup_test_app_with_state(AppState::new(pool, Cache::new(64)).await.with_history(true)).await
+}
+
+pub async fn setup_test_app_with_state(
+    state: AppState,
+) -> impl actix_web::dev::Service<
+    actix_http::Request,
+    Response = actix_web::dev::ServiceResponse,
+    Error = actix_web::Error,
+> {
     actix_web::test::init_service(
         actix_web::App::new()
             .app_data(actix_web::web::Data::new(state))
+            .wrap(actix_web::middleware::from_fn(rate_limit::limit))
+            .wrap(actix_web::middleware::from_fn(auth::authenticate))
+            .wrap(actix_web::middleware::from_fn(metrics::track))
             .configure(routes::init_routes),
     )
     .await
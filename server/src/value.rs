@@ -0,0 +1,40 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+/// How a stored value is represented in JSON responses: UTF-8 values as plain strings, anything
+/// else base64-encoded as `{"base64": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonValue {
+    Text(String),
+    Binary { base64: String },
+}
+
+impl From<&[u8]> for JsonValue {
+    fn from(value: &[u8]) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(text) => JsonValue::Text(text.to_string()),
+            Err(_) => JsonValue::Binary {
+                base64: STANDARD.encode(value),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonValue;
+    use serde_json::json;
+
+    #[test]
+    fn binary_values_are_base64_encoded() {
+        let text = JsonValue::from("value_1".as_bytes());
+        assert_eq!(serde_json::to_value(&text).unwrap(), json!("value_1"));
+
+        let binary = JsonValue::from([0xff, 0x00].as_slice());
+        assert_eq!(
+            serde_json::to_value(&binary).unwrap(),
+            json!({"base64": "/wA="})
+        );
+    }
+}
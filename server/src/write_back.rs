@@ -0,0 +1,585 @@
+use crate::cache::{CacheEntry, CacheKey};
+use crate::invalidation::{self, Invalidation};
+use crate::journal;
+use crate::state::AppState;
+use crate::store;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// A write accepted into the cache but not yet written to the database.
+#[derive(Debug, Clone)]
+struct Dirty {
+    entry: CacheEntry,
+    /// When the pair was first written after its last flush.
+    since: Instant,
+}
+
+/// Pairs to write back to the database.
+#[derive(Debug, Clone, Copy)]
+pub enum Scope<'a> {
+    All,
+    Namespace(&'a str),
+    Key(&'a CacheKey),
+}
+
+impl Scope<'_> {
+    fn contains(&self, key: &CacheKey) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Namespace(namespace) => key.namespace == *namespace,
+            Scope::Key(other) => key == *other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteBackStats {
+    pub write_back: bool,
+    /// pairs waiting to be written to the database
+    pub dirty: u64,
+    /// age of the oldest write waiting to be written to the database
+    pub flush_lag_ms: u64,
+    /// writes dropped as the database rejected them
+    pub dropped: u64,
+}
+
+/// Buffers writes in memory and writes them to the database in batches, so that a write does not
+/// wait for the database. Disabled unless built with [`WriteBack::new`].
+#[derive(Debug, Clone, Default)]
+pub struct WriteBack {
+    enabled: bool,
+    max_batch: usize,
+    /// Pairs buffered at most. Writes of other pairs wait for the buffer to be written back
+    /// beyond, rather than growing it while the database lags behind.
+    max_dirty: usize,
+    dirty: Arc<Mutex<HashMap<CacheKey, Dirty>>>,
+    /// Pairs being written by a flush. Each pair is written by one flush at a time, so that its
+    /// writes are applied in order, while flushes of other pairs go ahead.
+    claimed: Arc<Mutex<HashSet<CacheKey>>>,
+    /// Notified whenever a flush releases its pairs.
+    released: Arc<Notify>,
+    full: Arc<Notify>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl WriteBack {
+    /// Enables write-back, flushing in batches of at most `max_batch` pairs.
+    pub fn new(max_batch: usize) -> Self {
+        Self {
+            enabled: true,
+            max_batch: max_batch.max(1),
+            max_dirty: usize::MAX,
+            ..Self::default()
+        }
+    }
+
+    /// Buffers at most `max_dirty` pairs, rather than any number of them.
+    pub fn with_max_dirty(mut self, max_dirty: usize) -> Self {
+        self.max_dirty = max_dirty.max(1);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The pending write of `key`, if any. Always takes precedence over the cache and the
+    /// database.
+    pub fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let dirty = self.dirty.lock().unwrap();
+        dirty.get(key).map(|dirty| dirty.entry.clone())
+    }
+
+    /// Resolves once a full batch is waiting to be flushed.
+    pub async fn full(&self) {
+        self.full.notified().await
+    }
+
+    pub fn stats(&self) -> WriteBackStats {
+        let dirty = self.dirty.lock().unwrap();
+        WriteBackStats {
+            write_back: self.enabled,
+            dirty: dirty.len() as u64,
+            flush_lag_ms: dirty
+                .values()
+                .map(|dirty| dirty.since.elapsed().as_millis() as u64)
+                .max()
+                .unwrap_or(0),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forgets the pending writes of `pairs`, unless written again since. Returns the keys
+    /// forgotten.
+    fn forget<'a>(&self, pairs: &'a [(CacheKey, CacheEntry)]) -> Vec<&'a CacheKey> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let mut forgotten = Vec::with_capacity(pairs.len());
+        for (key, entry) in pairs {
+            if dirty
+                .get(key)
+                .is_some_and(|dirty| dirty.entry.version == entry.version)
+            {
+                dirty.remove(key);
+                forgotten.push(key);
+            }
+        }
+        forgotten
+    }
+
+    /// Claims the pairs of `keys` that are still dirty and not claimed by another flush, returning
+    /// their pending writes. Removes the pairs claimed or no longer dirty from `keys`.
+    fn claim(&self, keys: &mut HashSet<CacheKey>) -> (Claim, Vec<(CacheKey, CacheEntry)>) {
+        let dirty = self.dirty.lock().unwrap();
+        let mut claimed = self.claimed.lock().unwrap();
+        let mut pending = Vec::new();
+        keys.retain(|key| match dirty.get(key) {
+            Some(_) if claimed.contains(key) => true,
+            Some(dirty) => {
+                claimed.insert(key.clone());
+                pending.push((key.clone(), dirty.entry.clone()));
+                false
+            }
+            None => false,
+        });
+
+        let claim = Claim {
+            write_back: self.clone(),
+            keys: pending.iter().map(|(key, _)| key.clone()).collect(),
+        };
+        (claim, pending)
+    }
+}
+
+/// Pairs claimed by a flush, released when dropped, even if the flush fails or is cancelled.
+struct Claim {
+    write_back: WriteBack,
+    keys: Vec<CacheKey>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut claimed = self.write_back.claimed.lock().unwrap();
+        for key in &self.keys {
+            claimed.remove(key);
+        }
+        drop(claimed);
+        self.write_back.released.notify_waiters();
+    }
+}
+
+/// Accepts a write into the cache, to be written to the database by a later [`flush`]. Returns
+/// the entry as cached.
+pub async fn write(
+    state: &AppState,
+    key: CacheKey,
+    value: impl Into<actix_web::web::Bytes>,
+    content_type: &str,
+    ttl_seconds: Option<i32>,
+) -> sqlx::Result<CacheEntry> {
+    let write_back = &state.write_back;
+    // drawn when the write is accepted, as with direct writes, so that a pair written both ways
+    // never goes back to an earlier version
+    let version = sqlx::query_scalar!(r#"SELECT nextval('kv_store_version_seq') AS "version!""#)
+        .fetch_one(&state.db_pool)
+        .await?;
+    let expires_at: Option<DateTime<Utc>> =
+        ttl_seconds.map(|ttl| Utc::now() + TimeDelta::seconds(ttl.into()));
+    let entry = CacheEntry::new(value, content_type, version, expires_at);
+
+    let overflowing = {
+        let dirty = write_back.dirty.lock().unwrap();
+        dirty.len() >= write_back.max_dirty && !dirty.contains_key(&key)
+    };
+    if overflowing {
+        // the writer waits for the database instead, failing if it is down
+        flush(state, Scope::All).await?;
+    }
+
+    let full = {
+        let mut dirty = write_back.dirty.lock().unwrap();
+        let since = dirty
+            .get(&key)
+            .map_or_else(Instant::now, |dirty| dirty.since);
+        dirty.insert(
+            key.clone(),
+            Dirty {
+                entry: entry.clone(),
+                since,
+            },
+        );
+        dirty.len() >= write_back.max_batch
+    };
+    if full {
+        write_back.full.notify_one();
+    }
+
+    state.cache.write(key, entry.clone()).await;
+
+    Ok(entry)
+}
+
+/// Writes the pending writes within `scope` to the database, in batches, returning how many pairs
+/// were written. Must run before anything reads or writes those pairs in the database directly.
+pub async fn flush(state: &AppState, scope: Scope<'_>) -> sqlx::Result<usize> {
+    let write_back = &state.write_back;
+    if !write_back.enabled {
+        return Ok(0);
+    }
+
+    // pairs written again once flushed are left to later flushes
+    let mut keys: HashSet<CacheKey> = {
+        let dirty = write_back.dirty.lock().unwrap();
+        dirty
+            .keys()
+            .filter(|key| scope.contains(key))
+            .cloned()
+            .collect()
+    };
+
+    let mut written = 0;
+    while !keys.is_empty() {
+        // registered before claiming, so that no release goes unnoticed
+        let released = write_back.released.notified();
+        let (claim, pending) = write_back.claim(&mut keys);
+        if pending.is_empty() {
+            if !keys.is_empty() {
+                // the other flushes writing the remaining pairs must be done before this returns
+                released.await;
+            }
+            continue;
+        }
+
+        for batch in pending.chunks(write_back.max_batch) {
+            written += write_pairs(state, batch).await?;
+        }
+        drop(claim);
+    }
+
+    Ok(written)
+}
+
+/// Writes `pairs` in a single transaction, returning how many were written. Should the database
+/// reject them, writes them one by one instead, dropping the ones it still rejects so that they
+/// do not hold back the others.
+async fn write_pairs(state: &AppState, pairs: &[(CacheKey, CacheEntry)]) -> sqlx::Result<usize> {
+    let write_back = &state.write_back;
+    match write_batch(state, pairs).await {
+        Ok(superseded) => {
+            // pairs written again in the meantime stay dirty
+            let forgotten = write_back.forget(pairs);
+            for key in &superseded {
+                // the cache holds the superseded write, unless written again since
+                if forgotten.contains(key) {
+                    state.cache.remove(key).await;
+                }
+            }
+            Ok(pairs.len() - superseded.len())
+        }
+        Err(err) if is_rejected(&err) && pairs.len() > 1 => {
+            let mut written = 0;
+            for pair in pairs {
+                written += Box::pin(write_pairs(state, slice::from_ref(pair))).await?;
+            }
+            Ok(written)
+        }
+        Err(err) if is_rejected(&err) => {
+            for key in write_back.forget(pairs) {
+                tracing::warn!(
+                    "dropping write of {}/{} rejected by the database: {:?}",
+                    key.namespace,
+                    key.key,
+                    err
+                );
+                write_back.dropped.fetch_add(1, Ordering::Relaxed);
+                // never stored, so it must not be served either
+                state.cache.remove(key).await;
+            }
+            Ok(0)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Writes `pairs` in a single transaction, returning the keys of the pairs left out as they were
+/// stored at a later version meanwhile.
+async fn write_batch<'a>(
+    state: &AppState,
+    pairs: &'a [(CacheKey, CacheEntry)],
+) -> sqlx::Result<Vec<&'a CacheKey>> {
+    let puts: Vec<_> = pairs
+        .iter()
+        .map(|(key, entry)| journal::Put {
+            namespace: &key.namespace,
+            key: &key.key,
+            version: entry.version,
+            value: &entry.value,
+            content_type: &entry.content_type,
+            expires_at: entry.expires_at,
+        })
+        .collect();
+
+    let mut tx = state.db_pool.begin().await?;
+    let written: HashSet<_> = store::upsert_versioned(&mut *tx, &puts)
+        .await?
+        .into_iter()
+        .collect();
+    let mut invalidations = Vec::with_capacity(written.len());
+    let mut superseded = Vec::new();
+    for ((key, _), put) in pairs.iter().zip(&puts) {
+        if !written.contains(&(key.namespace.clone(), key.key.clone())) {
+            superseded.push(key);
+            continue;
+        }
+        journal::record_put(&mut tx, state.history, put).await?;
+        invalidations.push(Invalidation::key(
+            &state.instance_id,
+            &key.namespace,
+            &key.key,
+        ));
+    }
+    invalidation::publish(&mut *tx, &invalidations).await?;
+    tx.commit().await?;
+
+    Ok(superseded)
+}
+
+/// Whether the database rejected the data itself (data exceptions and integrity violations), so
+/// that writing it again would fail the same way, unlike with connection errors.
+fn is_rejected(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|err| err.code())
+        .is_some_and(|code| code.starts_with("22") || code.starts_with("23"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flush, Scope, WriteBack, WriteBackStats};
+    use crate::cache::{Cache, CacheKey};
+    use crate::namespace::DEFAULT_NAMESPACE;
+    use crate::state::AppState;
+    use crate::test_utils::setup_app::setup_test_app_with_state;
+    use actix_rt::time::timeout;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn writes_are_buffered_until_flushed(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool.clone(), Cache::new(64))
+            .await
+            .with_history(true)
+            .with_write_back(WriteBack::new(100));
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "key_1", "value": "value_1"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let stored = sqlx::query_scalar!("SELECT COUNT(*) FROM kv_store")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, Some(0));
+
+        // reads see the buffered write, even once it is no longer cached
+        state.cache.flush();
+        let req = test::TestRequest::get().uri("/key_1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+        let body = test::read_body(res).await;
+        assert_eq!(str::from_utf8(&body).unwrap(), "value_1");
+
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let stats: WriteBackStats = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats.dirty, 1);
+
+        assert_eq!(flush(&state, Scope::All).await?, 1);
+        assert_eq!(state.write_back.stats().dirty, 0);
+
+        let value = sqlx::query_scalar!("SELECT value FROM kv_store WHERE key = $1", "key_1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(value, b"value_1");
+
+        // other writes go through the database, after writing back the pair they touch
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "counter", "value": "41"}))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_incr/counter")
+            .set_json(json!({}))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res, json!({"value": 42}));
+        assert_eq!(state.write_back.stats().dirty, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn versions_only_increase_across_write_paths(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool.clone(), Cache::new(64))
+            .await
+            .with_write_back(WriteBack::new(100));
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        let mut versions = Vec::new();
+        for (uri, body) in [
+            ("/", json!({"key": "counter", "value": "1"})),
+            ("/_incr/counter", json!({})),
+            ("/", json!({"key": "counter", "value": "5"})),
+            ("/_incr/counter", json!({})),
+            ("/", json!({"key": "counter", "value": "9"})),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .set_json(body)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(res.status().is_success(), "{}", uri);
+            let etag = res.headers().get(header::ETAG).unwrap();
+            versions.push(
+                etag.to_str()
+                    .unwrap()
+                    .trim_matches('"')
+                    .parse::<i64>()
+                    .unwrap(),
+            );
+        }
+        assert!(versions.is_sorted());
+
+        flush(&state, Scope::All).await?;
+        let row = sqlx::query!("SELECT value, version FROM kv_store WHERE key = 'counter'")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!((row.value, row.version), (b"9".to_vec(), versions[4]));
+
+        // a pending write superseded by a later version in the database is left out
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(json!({"key": "counter", "value": "10"}))
+            .to_request();
+        test::call_service(&app, req).await;
+        sqlx::query("UPDATE kv_store SET value = '11', version = nextval('kv_store_version_seq')")
+            .execute(&pool)
+            .await?;
+
+        assert_eq!(flush(&state, Scope::All).await?, 0);
+        let req = test::TestRequest::get().uri("/counter").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(str::from_utf8(&body).unwrap(), "11");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn full_buffers_are_written_back_before_growing(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool.clone(), Cache::new(64))
+            .await
+            .with_write_back(WriteBack::new(100).with_max_dirty(2));
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        // pairs already buffered are written again in place
+        for key in ["key_1", "key_2", "key_2", "key_3"] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": key, "value": "value"}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+        }
+
+        assert_eq!(state.write_back.stats().dirty, 1);
+        let stored = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM kv_store"#)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rejected_writes_do_not_hold_back_others(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool.clone(), Cache::new(64))
+            .await
+            .with_write_back(WriteBack::new(100));
+        let app = setup_test_app_with_state(state.clone()).await;
+
+        // stands for any value the database refuses once the write was accepted
+        sqlx::query("ALTER TABLE kv_store ADD CONSTRAINT no_key_2 CHECK (key <> 'key_2')")
+            .execute(&pool)
+            .await?;
+        for key in ["key_1", "key_2", "key_3"] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": key, "value": "value"}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+        }
+
+        assert_eq!(flush(&state, Scope::All).await?, 2);
+        let stats = state.write_back.stats();
+        assert_eq!((stats.dirty, stats.dropped), (0, 1));
+
+        let stored = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM kv_store"#)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, 2);
+
+        let req = test::TestRequest::get().uri("/key_2").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn key_flushes_only_wait_for_their_pair(pool: PgPool) -> sqlx::Result<()> {
+        let state = AppState::new(pool, Cache::new(64))
+            .await
+            .with_write_back(WriteBack::new(100));
+        let app = setup_test_app_with_state(state.clone()).await;
+        for key in ["key_1", "key_2"] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_json(json!({"key": key, "value": "value"}))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        // as if a flush of every pair were writing key_1
+        let key_1 = CacheKey::new(DEFAULT_NAMESPACE, "key_1");
+        let (claim, pending) = state.write_back.claim(&mut HashSet::from([key_1.clone()]));
+        assert_eq!(pending.len(), 1);
+
+        let key_2 = CacheKey::new(DEFAULT_NAMESPACE, "key_2");
+        assert_eq!(flush(&state, Scope::Key(&key_2)).await?, 1);
+
+        let waiting = timeout(
+            Duration::from_millis(100),
+            flush(&state, Scope::Key(&key_1)),
+        )
+        .await;
+        assert!(waiting.is_err());
+        drop(claim);
+        assert_eq!(flush(&state, Scope::Key(&key_1)).await?, 1);
+
+        Ok(())
+    }
+}